@@ -1,15 +1,29 @@
-use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::env;
 use std::sync::Arc;
-use tokio::sync::{oneshot, Mutex};
-use tokio::time::{sleep, Duration, Instant};
-use tracing::{debug, error};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, oneshot, Mutex, MutexGuard};
+use tokio::time::{interval_at, sleep, Duration, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
+use crate::challenge::{
+    pick_challenge_kind, Challenge, ChallengeKind, CustomCategory, WeightedChallengeKind,
+};
+use crate::i18n::{tr, Language};
 use crate::matrix::Matrix;
 use crate::openobserve::{OpenObserve, UserEvent};
+use crate::penalty::{Penalty, StrikeMode, Strikes};
+use crate::response_times::ResponseTimes;
+use crate::storage::Storage;
 use crate::telegram::*;
+use crate::welcome::{escape_html, WelcomeConfig};
+
+const BOT_CONFIGS_FILE: &str = "bot_configs";
+const PENDING_CHALLENGES_FILE: &str = "pending_challenges";
+const STRIKES_FILE: &str = "strikes";
+const RESPONSE_TIMES_FILE: &str = "response_times";
 
 // --- Bot Configuration Functions ---
 
@@ -25,7 +39,12 @@ pub async fn delete_messages_after_delay(
 
         for message_id in message_ids {
             if let Err(e) = telegram_client.delete_message(chat_id, message_id).await {
-                debug!("Failed to delete cleanup message {}: {}", message_id, e);
+                if e.error_code() == Some(400) {
+                    // Already deleted by someone else or too old to delete
+                    debug!("Cleanup message {} is gone: {}", message_id, e);
+                } else {
+                    error!("Failed to delete cleanup message {}: {}", message_id, e);
+                }
             } else {
                 debug!(
                     "Cleanup: deleted message {} after {} seconds",
@@ -37,334 +56,1629 @@ pub async fn delete_messages_after_delay(
 }
 
 pub async fn get_or_create_bot_config(config_state: &BotConfigState, chat_id: i64) -> BotConfig {
-    let mut state = config_state.lock().await;
-    state
-        .entry(chat_id)
-        .or_insert_with(|| BotConfig {
-            whitelisted_bots: Vec::new(),
-            notify_on_ban: true,
-            banned_bots_count: 0,
-        })
-        .clone()
+    let mut state = config_state.configs.lock().await;
+    state.entry(chat_id).or_default().clone()
+}
+
+// Applies `update` to the chat's configuration and writes the whole state back to disk
+pub async fn update_bot_config<F, R>(config_state: &BotConfigState, chat_id: i64, update: F) -> R
+where
+    F: FnOnce(&mut BotConfig) -> R,
+{
+    let mut state = config_state.configs.lock().await;
+    let result = update(state.entry(chat_id).or_default());
+    if let Err(e) = config_state.storage.save(BOT_CONFIGS_FILE, &*state).await {
+        error!("Failed to persist bot configuration: {}", e);
+    }
+    result
+}
+
+// What a verified member gets back: the chat's override, else the chat's own defaults
+pub async fn member_permissions(
+    telegram_client: &Telegram,
+    bot_config_state: &BotConfigState,
+    chat_id: i64,
+) -> ChatPermissions {
+    let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+    if let Some(permissions) = config.member_permissions {
+        return permissions;
+    }
+    match telegram_client.get_chat(chat_id).await {
+        Ok(ChatFullInfo {
+            permissions: Some(permissions),
+            ..
+        }) => permissions,
+        Ok(_) => ChatPermissions::member_defaults(),
+        Err(e) => {
+            error!(
+                "Failed to read default permissions of chat {}: {}",
+                chat_id, e
+            );
+            ChatPermissions::member_defaults()
+        }
+    }
+}
+
+pub async fn restore_member_permissions(
+    telegram_client: &Telegram,
+    bot_config_state: &BotConfigState,
+    chat_id: i64,
+    user_id: i64,
+) -> Result<(), TelegramError> {
+    let permissions = member_permissions(telegram_client, bot_config_state, chat_id).await;
+    telegram_client
+        .restrict_chat_member(chat_id, user_id, &permissions)
+        .await
+}
+
+// Language of the chat's notices and command replies
+pub async fn chat_language(bot_config_state: &BotConfigState, chat_id: i64) -> Language {
+    get_or_create_bot_config(bot_config_state, chat_id)
+        .await
+        .language
+}
+
+// Posts the challenge with its keyboard; image challenges go as a photo whose
+// caption carries the text
+async fn send_challenge(
+    telegram_client: &Telegram,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    text: &str,
+    puzzle: &dyn Challenge,
+) -> Result<u64, TelegramError> {
+    match puzzle.image() {
+        Some(image) => {
+            telegram_client
+                .send_photo(
+                    chat_id,
+                    message_thread_id,
+                    challenge_photo(image),
+                    text,
+                    puzzle.keyboard(),
+                )
+                .await
+        }
+        None => {
+            telegram_client
+                .send_message_with_keyboard(chat_id, message_thread_id, text, puzzle.keyboard())
+                .await
+        }
+    }
+}
+
+// Announces a private-mode challenge in the group with a button to the bot's chat
+async fn send_deep_link_prompt(
+    telegram_client: &Telegram,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    language: Language,
+    first_name: &str,
+    deadline: u64,
+    token: &str,
+) -> Result<(u64, ChallengeMessage), TelegramError> {
+    let username = telegram_client.bot_username().await?;
+    let keyboard = InlineKeyboardMarkup {
+        inline_keyboard: vec![vec![InlineKeyboardButton {
+            text: tr!(language, "challenge.private_button"),
+            url: Some(format!(
+                "https://t.me/{}?start={}{}",
+                username, DEEP_LINK_PREFIX, token
+            )),
+            callback_data: None,
+        }]],
+    };
+    let message = ChallengeMessage {
+        body: tr!(
+            language,
+            "challenge.private_prompt",
+            name = escape_html(first_name)
+        ),
+        keyboard,
+        photo: false,
+    };
+    let message_id = telegram_client
+        .send_message_with_keyboard(
+            chat_id,
+            message_thread_id,
+            &with_time_left(language, &message.body, deadline),
+            message.keyboard.clone(),
+        )
+        .await?;
+    Ok((message_id, message))
+}
+
+// Payload of the /start command a deep link opens the private chat with
+pub const DEEP_LINK_PREFIX: &str = "verify_";
+
+// Sends the challenge behind a deep link to the private chat of the user who opened
+// it, with a fresh puzzle and the too-fast clock restarted. Opening the link again
+// replaces the previous message. Returns false when the token is not one of the
+// user's pending challenges.
+pub async fn open_deep_link(
+    telegram_client: &Arc<Telegram>,
+    challenge_state: &ChallengeState,
+    bot_config_state: &BotConfigState,
+    private_chat_id: i64,
+    user_id: i64,
+    token: &str,
+) -> bool {
+    let (chat_id, puzzle, message, text, previous_message_id) = {
+        let state_guard = challenge_state.lock().await;
+        let Some((chat_id, challenge)) = state_guard.iter().find_map(|(chat_id, users)| {
+            users
+                .get(&user_id)
+                .filter(|challenge| {
+                    challenge
+                        .deep_link
+                        .as_ref()
+                        .is_some_and(|link| link.token == token)
+                })
+                .map(|challenge| (*chat_id, challenge))
+        }) else {
+            return false;
+        };
+
+        let language = challenge.language;
+        let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+        let puzzle = challenge
+            .puzzle
+            .kind()
+            .generate(language, &config.custom_categories);
+        let chat = challenge
+            .chat_title
+            .as_deref()
+            .map(escape_html)
+            .unwrap_or_else(|| tr!(language, "challenge.default_chat_title"));
+        let message = ChallengeMessage::new(
+            tr!(
+                language,
+                "challenge.private",
+                name = escape_html(&challenge.user_name),
+                chat = chat,
+                question = puzzle.question(),
+            ),
+            puzzle.as_ref(),
+        );
+        let text = with_time_left(language, &message.body, challenge.deadline);
+        // A link opened again replaces the challenge it already sent
+        let previous_message_id = challenge
+            .private_chat_id()
+            .map(|_| challenge.challenge_message_id);
+        (chat_id, puzzle, message, text, previous_message_id)
+    };
+
+    match send_challenge(
+        telegram_client,
+        private_chat_id,
+        None,
+        &text,
+        puzzle.as_ref(),
+    )
+    .await
+    {
+        Ok(message_id) => {
+            debug!(
+                "Deep-link challenge of chat {} sent to user {}: Message ID {}",
+                chat_id, user_id, message_id
+            );
+            if let Some(previous_message_id) = previous_message_id {
+                let _ = telegram_client
+                    .delete_message(private_chat_id, previous_message_id)
+                    .await;
+            }
+            let mut state_guard = challenge_state.lock().await;
+            let Some(challenge) = state_guard
+                .get_mut(&chat_id)
+                .and_then(|chat_challenges| chat_challenges.get_mut(&user_id))
+            else {
+                // Answered elsewhere or expired while the message was on its way
+                drop(state_guard);
+                let _ = telegram_client
+                    .delete_message(private_chat_id, message_id)
+                    .await;
+                return true;
+            };
+            if let Some(link) = challenge.deep_link.as_mut() {
+                link.private_chat_id = Some(private_chat_id);
+            }
+            challenge.puzzle = puzzle;
+            challenge.challenge_message_id = message_id;
+            challenge.message = Some(message);
+            challenge.start_time = Instant::now();
+            challenge_state.persist(&state_guard).await;
+        }
+        Err(e) => error!(
+            "Failed to send deep-link challenge of chat {} to user {}: {}",
+            chat_id, user_id, e
+        ),
+    }
+    true
+}
+
+// Remaining time of a challenge as m:ss
+fn format_time_left(deadline: u64) -> String {
+    let seconds_left = deadline.saturating_sub(unix_now());
+    format!("{}:{:02}", seconds_left / 60, seconds_left % 60)
+}
+
+// Challenge messages end with the time left, which the countdown keeps up to date
+fn with_time_left(language: Language, body: &str, deadline: u64) -> String {
+    format!(
+        "{}\n\n{}",
+        body,
+        tr!(
+            language,
+            "challenge.time_left",
+            time_left = format_time_left(deadline)
+        )
+    )
+}
+
+// The text of a photo message is its caption
+async fn edit_challenge_message(
+    telegram_client: &Telegram,
+    chat_id: i64,
+    message_id: u64,
+    photo: bool,
+    text: &str,
+    keyboard: InlineKeyboardMarkup,
+) -> Result<(), TelegramError> {
+    if photo {
+        telegram_client
+            .edit_message_caption(chat_id, message_id, text, keyboard)
+            .await
+    } else {
+        telegram_client
+            .edit_message_text(chat_id, message_id, text, keyboard)
+            .await
+    }
+}
+
+// Rewrites the time left of a pending challenge. The lock is not held during the
+// edit, the challenge's edit lock is: its retry and its outcome take it as well, so
+// a countdown can never bring old buttons back over them. A tick that finds another
+// edit in progress is skipped.
+async fn refresh_countdown(
+    telegram_client: &Telegram,
+    state: &ChallengeState,
+    chat_id: i64,
+    user_id: i64,
+) {
+    let chat_id = telegram_client.resolve_chat_id(chat_id);
+    let edit_lock = state
+        .lock()
+        .await
+        .get(&chat_id)
+        .and_then(|chat_challenges| chat_challenges.get(&user_id))
+        .map(|challenge| Arc::clone(&challenge.edit_lock));
+    let Some(edit_lock) = edit_lock else {
+        return;
+    };
+    let Ok(_editing) = edit_lock.try_lock() else {
+        debug!(
+            "Skipping a countdown update of user {} in chat {}, the message is being edited",
+            user_id, chat_id
+        );
+        return;
+    };
+
+    // Read again under the edit lock: the challenge may be over or replaced by now
+    let (message_chat_id, message_id, photo, text, keyboard) = {
+        let state_guard = state.lock().await;
+        let Some(challenge) = state_guard
+            .get(&chat_id)
+            .and_then(|chat_challenges| chat_challenges.get(&user_id))
+        else {
+            return;
+        };
+        let Some(message) = &challenge.message else {
+            return;
+        };
+        (
+            challenge.private_chat_id().unwrap_or(chat_id),
+            challenge.challenge_message_id,
+            message.photo,
+            with_time_left(challenge.language, &message.body, challenge.deadline),
+            message.keyboard.clone(),
+        )
+    };
+    if let Err(e) = edit_challenge_message(
+        telegram_client,
+        message_chat_id,
+        message_id,
+        photo,
+        &text,
+        keyboard,
+    )
+    .await
+    {
+        debug!(
+            "Failed to update the countdown of message {} in chat {}: {}",
+            message_id, message_chat_id, e
+        );
+    }
+}
+
+// Shows the outcome of a finished challenge in its group message, without the
+// buttons, and removes it after the cleanup delay. If the message cannot be edited
+// the outcome is sent as a new one. A deep-link challenge also loses its buttons in
+// the private chat.
+pub async fn announce_outcome(
+    telegram_client: &Arc<Telegram>,
+    chat_id: i64,
+    challenge: &ChallengeDetails,
+    text: &str,
+) {
+    show_outcome(
+        telegram_client,
+        chat_id,
+        challenge,
+        text,
+        InlineKeyboardMarkup::default(),
+        false,
+    )
+    .await;
+}
+
+// Like announce_outcome, with the chat's welcome: its text, its link buttons and
+// whether it stays
+pub async fn announce_welcome(
+    telegram_client: &Arc<Telegram>,
+    chat_id: i64,
+    challenge: &ChallengeDetails,
+    user_id: i64,
+    welcome: &WelcomeConfig,
+) {
+    let text = welcome.render(
+        challenge.language,
+        user_id,
+        &challenge.user_name,
+        challenge.chat_title.as_deref(),
+    );
+    show_outcome(
+        telegram_client,
+        chat_id,
+        challenge,
+        &text,
+        welcome.keyboard(),
+        welcome.keep,
+    )
+    .await;
+}
+
+async fn show_outcome(
+    telegram_client: &Arc<Telegram>,
+    chat_id: i64,
+    challenge: &ChallengeDetails,
+    text: &str,
+    keyboard: InlineKeyboardMarkup,
+    keep: bool,
+) {
+    let _editing = challenge.edit_lock.lock().await;
+    let group_message_id = challenge.group_message_id();
+    // The prompt of a deep link is always a text message
+    let photo = challenge.deep_link.is_none() && challenge.puzzle.kind() == ChallengeKind::Image;
+    let mut messages_to_delete = Vec::new();
+    match edit_challenge_message(
+        telegram_client,
+        chat_id,
+        group_message_id,
+        photo,
+        text,
+        keyboard.clone(),
+    )
+    .await
+    {
+        Ok(()) if keep => {}
+        Ok(()) => messages_to_delete.push(group_message_id),
+        Err(e) => {
+            warn!(
+                "Failed to show the outcome in message {} of chat {}: {}",
+                group_message_id, chat_id, e
+            );
+            messages_to_delete.push(group_message_id);
+            let sent = if keyboard.inline_keyboard.is_empty() {
+                telegram_client
+                    .send_message(chat_id, challenge.message_thread_id, text)
+                    .await
+            } else {
+                telegram_client
+                    .send_message_with_keyboard(
+                        chat_id,
+                        challenge.message_thread_id,
+                        text,
+                        keyboard,
+                    )
+                    .await
+            };
+            if let Ok(msg_id) = sent {
+                if !keep {
+                    messages_to_delete.push(msg_id);
+                }
+            }
+        }
+    }
+    delete_messages_after_delay(
+        telegram_client.clone(),
+        chat_id,
+        messages_to_delete,
+        cleanup_delay_seconds(),
+    )
+    .await;
+
+    if let Some(private_chat_id) = challenge
+        .deep_link
+        .as_ref()
+        .and_then(|link| link.private_chat_id)
+    {
+        if let Err(e) = telegram_client
+            .edit_message_reply_markup(
+                private_chat_id,
+                challenge.challenge_message_id,
+                InlineKeyboardMarkup::default(),
+            )
+            .await
+        {
+            debug!(
+                "Failed to remove the buttons of message {} in chat {}: {}",
+                challenge.challenge_message_id, private_chat_id, e
+            );
+        }
+    }
+}
+
+fn challenge_photo(image: &[u8]) -> InputFile {
+    InputFile {
+        field: "photo",
+        file_name: "challenge.png".to_string(),
+        mime_type: "image/png",
+        bytes: image.to_vec(),
+    }
+}
+
+pub fn attempts_left_text(language: Language, attempts_left: u32) -> String {
+    if attempts_left == 1 {
+        tr!(language, "challenge.attempts_left_one")
+    } else {
+        tr!(
+            language,
+            "challenge.attempts_left_many",
+            attempts = attempts_left
+        )
+    }
+}
+
+// After a wrong answer that still leaves attempts, swaps the challenge message for a
+// fresh challenge of the same kind, so retrying is not a matter of elimination. If
+// the message cannot be edited the old challenge stays valid. The edit is made
+// without the lock, and the new challenge only replaces the old one if it is still
+// pending afterwards.
+pub async fn retry_challenge(
+    telegram_client: &Telegram,
+    bot_config_state: &BotConfigState,
+    challenge_state: &ChallengeState,
+    chat_id: i64,
+    user_id: i64,
+) {
+    let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+    let (puzzle, message, text, message_chat_id, message_id, edit_lock) = {
+        let state_guard = challenge_state.lock().await;
+        let Some(challenge) = state_guard
+            .get(&chat_id)
+            .and_then(|chat_challenges| chat_challenges.get(&user_id))
+        else {
+            return;
+        };
+        let language = challenge.language;
+        let puzzle = challenge
+            .puzzle
+            .kind()
+            .generate(language, &config.custom_categories);
+        let message = ChallengeMessage::new(
+            tr!(
+                language,
+                "challenge.retry",
+                name = escape_html(&challenge.user_name),
+                attempts_left = attempts_left_text(language, challenge.attempts_left),
+                question = puzzle.question(),
+            ),
+            puzzle.as_ref(),
+        );
+        let text = with_time_left(language, &message.body, challenge.deadline);
+        (
+            puzzle,
+            message,
+            text,
+            challenge.private_chat_id().unwrap_or(chat_id),
+            challenge.challenge_message_id,
+            Arc::clone(&challenge.edit_lock),
+        )
+    };
+    let _editing = edit_lock.lock().await;
+
+    let edited = match puzzle.image() {
+        Some(image) => {
+            telegram_client
+                .edit_message_photo(
+                    message_chat_id,
+                    message_id,
+                    challenge_photo(image),
+                    &text,
+                    puzzle.keyboard(),
+                )
+                .await
+        }
+        None => {
+            telegram_client
+                .edit_message_text(message_chat_id, message_id, &text, puzzle.keyboard())
+                .await
+        }
+    };
+    if let Err(e) = edited {
+        warn!(
+            "Failed to replace challenge message {} in chat {}: {}",
+            message_id, message_chat_id, e
+        );
+        return;
+    }
+
+    let mut state_guard = challenge_state.lock().await;
+    if let Some(challenge) = state_guard
+        .get_mut(&chat_id)
+        .and_then(|chat_challenges| chat_challenges.get_mut(&user_id))
+        .filter(|challenge| challenge.challenge_message_id == message_id)
+    {
+        // The too-fast check applies to the new challenge as well
+        challenge.puzzle = puzzle;
+        challenge.message = Some(message);
+        challenge.start_time = Instant::now();
+        challenge_state.persist(&state_guard).await;
+    }
+}
+
+// Topic where challenges and notifications go; only forum groups have topics
+pub async fn challenge_topic(bot_config_state: &BotConfigState, chat: &Chat) -> Option<i64> {
+    if chat.is_forum != Some(true) {
+        return None;
+    }
+    get_or_create_bot_config(bot_config_state, chat.id)
+        .await
+        .challenge_topic_id
+}
+
+// Moves everything we keep about a group to the supergroup it was upgraded to
+pub async fn migrate_chat(
+    telegram_client: &Telegram,
+    bot_config_state: &BotConfigState,
+    challenge_state: &ChallengeState,
+    from_chat_id: i64,
+    to_chat_id: i64,
+) {
+    info!(
+        "Chat {} was upgraded to supergroup {}, migrating its state",
+        from_chat_id, to_chat_id
+    );
+    telegram_client.remember_chat_migration(from_chat_id, to_chat_id);
+
+    {
+        let mut configs = bot_config_state.configs.lock().await;
+        if let Some(config) = configs.remove(&from_chat_id) {
+            configs.entry(to_chat_id).or_insert(config);
+            if let Err(e) = bot_config_state
+                .storage
+                .save(BOT_CONFIGS_FILE, &*configs)
+                .await
+            {
+                error!("Failed to persist bot configuration: {}", e);
+            }
+        }
+    }
+
+    challenge_state
+        .migrate_strikes(from_chat_id, to_chat_id)
+        .await;
+    challenge_state
+        .migrate_response_times(from_chat_id, to_chat_id)
+        .await;
+
+    let mut state_guard = challenge_state.lock().await;
+    if let Some(chat_challenges) = state_guard.remove(&from_chat_id) {
+        debug!(
+            "Moving {} pending challenges to chat {}",
+            chat_challenges.len(),
+            to_chat_id
+        );
+        state_guard
+            .entry(to_chat_id)
+            .or_default()
+            .extend(chat_challenges);
+        challenge_state.persist(&state_guard).await;
+    }
+}
+
+// Moves the state of the groups the Telegram client finds out were upgraded
+pub async fn follow_chat_migrations(
+    telegram_client: Arc<Telegram>,
+    bot_config_state: BotConfigState,
+    challenge_state: ChallengeState,
+    mut migrations: broadcast::Receiver<(i64, i64)>,
+) {
+    loop {
+        match migrations.recv().await {
+            Ok((from_chat_id, to_chat_id)) => {
+                migrate_chat(
+                    &telegram_client,
+                    &bot_config_state,
+                    &challenge_state,
+                    from_chat_id,
+                    to_chat_id,
+                )
+                .await;
+            }
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                warn!("Missed {} chat migrations", missed);
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
 }
 
 // --- Challenge Specific Functions ---
 
+pub fn challenge_duration() -> Duration {
+    let challenge_duration_minutes = env::var("CHALLENGE_DURATION_MINUTES")
+        .unwrap_or_else(|_| "2".to_string())
+        .parse::<u64>()
+        .unwrap_or(2);
+    Duration::from_secs(challenge_duration_minutes * 60)
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 pub struct ChallengeDetails {
-    pub correct_answer: String,
+    pub puzzle: Box<dyn Challenge>,
+    pub attempts_left: u32, // Wrong answers still tolerated, counting the next one
     pub challenge_message_id: u64,
     pub start_time: Instant,
-    pub tx: oneshot::Sender<()>, // Channel to signal completion or timeout
+    pub user_name: String,
+    pub chat_title: Option<String>,
+    pub started_at: u64, // Wall-clock start, seconds since the Unix epoch
+    pub deadline: u64,   // Wall-clock deadline, seconds since the Unix epoch
+    pub join_request_chat_id: Option<i64>, // Private chat holding a join-request challenge
+    pub message_thread_id: Option<i64>, // Forum topic the challenge was posted in
+    pub language: Language, // Language of the challenge and of the replies to it
+    pub deep_link: Option<DeepLink>, // Set when the challenge is taken in private through a link
+    pub message: Option<ChallengeMessage>, // What the countdown rewrites; unknown for old challenges
+    pub edit_lock: Arc<Mutex<()>>, // Held while the challenge message is edited, so edits land in order
+    pub tx: oneshot::Sender<()>,   // Channel to signal completion or timeout
+}
+
+// The challenge message as sent, without its time-left line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeMessage {
+    pub body: String,
+    pub keyboard: InlineKeyboardMarkup,
+    pub photo: bool, // Image challenges are photos whose caption carries the text
 }
 
-// State: Map of chat_id -> (Map of user_id -> ChallengeDetails)
-pub type ChallengeState = Arc<Mutex<HashMap<i64, HashMap<i64, ChallengeDetails>>>>;
+impl ChallengeMessage {
+    fn new(body: String, puzzle: &dyn Challenge) -> Self {
+        ChallengeMessage {
+            body,
+            keyboard: puzzle.keyboard(),
+            photo: puzzle.image().is_some(),
+        }
+    }
+}
+
+// A challenge the group only announces with a t.me link; the buttons are sent to the
+// user's private chat once they open it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeepLink {
+    pub token: String,
+    pub prompt_message_id: u64,       // The announcement in the group
+    pub private_chat_id: Option<i64>, // Where the challenge went, once the link is opened
+}
+
+impl ChallengeDetails {
+    // Chat holding the buttons when it is not the group: join requests and opened links
+    pub fn private_chat_id(&self) -> Option<i64> {
+        self.join_request_chat_id.or_else(|| {
+            self.deep_link
+                .as_ref()
+                .and_then(|link| link.private_chat_id)
+        })
+    }
+
+    // The group message to clean up once the challenge is over
+    pub fn group_message_id(&self) -> u64 {
+        self.deep_link
+            .as_ref()
+            .map_or(self.challenge_message_id, |link| link.prompt_message_id)
+    }
+}
+
+// What survives a restart of a ChallengeDetails
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingChallenge {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub user_name: String,
+    pub chat_title: Option<String>,
+    pub correct_answer: String, // Challenge::answer()
+    #[serde(default)]
+    pub kind: ChallengeKind,
+    #[serde(default = "default_attempts_left")]
+    pub attempts_left: u32,
+    pub challenge_message_id: u64,
+    pub started_at: u64,
+    pub deadline: u64,
+    #[serde(default)]
+    pub join_request_chat_id: Option<i64>,
+    #[serde(default)]
+    pub message_thread_id: Option<i64>,
+    #[serde(default)]
+    pub language: Language,
+    #[serde(default)]
+    pub deep_link: Option<DeepLink>,
+    #[serde(default)]
+    pub message: Option<ChallengeMessage>,
+}
+
+fn default_attempts_left() -> u32 {
+    1
+}
+
+// Map of chat_id -> (Map of user_id -> ChallengeDetails)
+pub type Challenges = HashMap<i64, HashMap<i64, ChallengeDetails>>;
+
+// How long a user approved through a join request is not challenged again on entry
+const JOIN_REQUEST_GRACE: Duration = Duration::from_secs(300);
+
+// State: pending challenges, mirrored under DATA_DIR after every change
+#[derive(Clone)]
+pub struct ChallengeState {
+    challenges: Arc<Mutex<Challenges>>,
+    approved_join_requests: Arc<Mutex<HashMap<(i64, i64), Instant>>>, // (chat_id, user_id)
+    strikes: Arc<Mutex<Strikes>>,
+    response_times: Arc<Mutex<HashMap<i64, ResponseTimes>>>, // chat_id -> passed answers
+    storage: Storage,
+}
+
+impl ChallengeState {
+    pub fn new(storage: Storage) -> Self {
+        let strikes: Strikes = storage.load(STRIKES_FILE);
+        let response_times: HashMap<i64, ResponseTimes> = storage.load(RESPONSE_TIMES_FILE);
+        ChallengeState {
+            challenges: Arc::new(Mutex::new(HashMap::new())),
+            approved_join_requests: Arc::new(Mutex::new(HashMap::new())),
+            strikes: Arc::new(Mutex::new(strikes)),
+            response_times: Arc::new(Mutex::new(response_times)),
+            storage,
+        }
+    }
+
+    // Counts a failure of the user in the chat and returns how many they have: in
+    // this chat, or in every chat when `global`
+    pub async fn record_strike(&self, chat_id: i64, user_id: i64, global: bool) -> u32 {
+        let mut strikes = self.strikes.lock().await;
+        let user_strikes = strikes.entry(user_id).or_default();
+        let chat_strikes = user_strikes.entry(chat_id).or_default();
+        *chat_strikes += 1;
+        let count = if global {
+            user_strikes.values().sum()
+        } else {
+            *chat_strikes
+        };
+        if let Err(e) = self.storage.save(STRIKES_FILE, &*strikes).await {
+            error!("Failed to persist strikes: {}", e);
+        }
+        count
+    }
+
+    async fn migrate_strikes(&self, from_chat_id: i64, to_chat_id: i64) {
+        let mut strikes = self.strikes.lock().await;
+        let mut moved = false;
+        for user_strikes in strikes.values_mut() {
+            if let Some(count) = user_strikes.remove(&from_chat_id) {
+                *user_strikes.entry(to_chat_id).or_default() += count;
+                moved = true;
+            }
+        }
+        if moved {
+            if let Err(e) = self.storage.save(STRIKES_FILE, &*strikes).await {
+                error!("Failed to persist strikes: {}", e);
+            }
+        }
+    }
+
+    pub async fn record_response_time(&self, chat_id: i64, response_time: Duration) {
+        let mut response_times = self.response_times.lock().await;
+        response_times
+            .entry(chat_id)
+            .or_default()
+            .record(response_time);
+        if let Err(e) = self
+            .storage
+            .save(RESPONSE_TIMES_FILE, &*response_times)
+            .await
+        {
+            error!("Failed to persist response times: {}", e);
+        }
+    }
+
+    // What the chat has learned so far about how fast its humans answer
+    pub async fn response_times(&self, chat_id: i64) -> ResponseTimes {
+        self.response_times
+            .lock()
+            .await
+            .get(&chat_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Answers quicker than this are taken as a bot's
+    pub async fn too_fast_threshold(&self, chat_id: i64) -> Duration {
+        self.response_times(chat_id)
+            .await
+            .threshold(min_response_time())
+    }
+
+    async fn migrate_response_times(&self, from_chat_id: i64, to_chat_id: i64) {
+        let mut response_times = self.response_times.lock().await;
+        if let Some(times) = response_times.remove(&from_chat_id) {
+            response_times.entry(to_chat_id).or_insert(times);
+            if let Err(e) = self
+                .storage
+                .save(RESPONSE_TIMES_FILE, &*response_times)
+                .await
+            {
+                error!("Failed to persist response times: {}", e);
+            }
+        }
+    }
+
+    pub async fn mark_join_request_approved(&self, chat_id: i64, user_id: i64) {
+        let mut approved = self.approved_join_requests.lock().await;
+        approved.retain(|_, approved_at| approved_at.elapsed() < JOIN_REQUEST_GRACE);
+        approved.insert((chat_id, user_id), Instant::now());
+    }
+
+    pub async fn was_join_request_approved(&self, chat_id: i64, user_id: i64) -> bool {
+        self.approved_join_requests
+            .lock()
+            .await
+            .get(&(chat_id, user_id))
+            .is_some_and(|approved_at| approved_at.elapsed() < JOIN_REQUEST_GRACE)
+    }
+
+    // Join-request and deep-link challenges are keyed by the group but answered in
+    // the user's private chat
+    pub fn find_private_challenge_chat(
+        challenges: &Challenges,
+        private_chat_id: i64,
+        user_id: i64,
+        message_id: u64,
+    ) -> Option<i64> {
+        challenges.iter().find_map(|(chat_id, users)| {
+            users
+                .get(&user_id)
+                .filter(|challenge| {
+                    challenge.private_chat_id() == Some(private_chat_id)
+                        && challenge.challenge_message_id == message_id
+                })
+                .map(|_| *chat_id)
+        })
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, Challenges> {
+        self.challenges.lock().await
+    }
+
+    // Takes a challenge out of the map, dropping its chat once it has none left.
+    // Whoever takes it resolves it, so an answer and a timeout never both do.
+    pub fn remove_challenge(
+        challenges: &mut Challenges,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Option<ChallengeDetails> {
+        let chat_challenges = challenges.get_mut(&chat_id)?;
+        let challenge = chat_challenges.remove(&user_id);
+        if chat_challenges.is_empty() {
+            challenges.remove(&chat_id);
+        }
+        challenge
+    }
+
+    // Locks just long enough to mirror the map, once a taken challenge is resolved
+    pub async fn sync(&self) {
+        let state_guard = self.lock().await;
+        self.persist(&state_guard).await;
+    }
+
+    // Call with the guard still held so the file always matches the map
+    pub async fn persist(&self, challenges: &Challenges) {
+        let pending: Vec<PendingChallenge> = challenges
+            .iter()
+            .flat_map(|(chat_id, users)| {
+                users
+                    .iter()
+                    .map(move |(user_id, challenge)| PendingChallenge {
+                        chat_id: *chat_id,
+                        user_id: *user_id,
+                        user_name: challenge.user_name.clone(),
+                        chat_title: challenge.chat_title.clone(),
+                        correct_answer: challenge.puzzle.answer().to_string(),
+                        kind: challenge.puzzle.kind(),
+                        attempts_left: challenge.attempts_left,
+                        challenge_message_id: challenge.challenge_message_id,
+                        started_at: challenge.started_at,
+                        deadline: challenge.deadline,
+                        join_request_chat_id: challenge.join_request_chat_id,
+                        message_thread_id: challenge.message_thread_id,
+                        language: challenge.language,
+                        deep_link: challenge.deep_link.clone(),
+                        message: challenge.message.clone(),
+                    })
+            })
+            .collect();
+        if let Err(e) = self.storage.save(PENDING_CHALLENGES_FILE, &pending).await {
+            error!("Failed to persist pending challenges: {}", e);
+        }
+    }
+}
 
 // Bot whitelist and admin configuration
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct BotConfig {
-    pub whitelisted_bots: Vec<i64>, // IDs de bots permitidos
-    pub notify_on_ban: bool,        // Notificar cuando se expulsa un bot
-    pub banned_bots_count: u64,     // Estadísticas de bots expulsados
-}
-
-pub type BotConfigState = Arc<Mutex<HashMap<i64, BotConfig>>>; // Por chat
-
-// Define categories and their emojis
-pub struct Category {
-    pub name: &'static str,
-    pub singular_form: &'static str,
-    pub emojis: &'static [&'static str],
-}
-
-const CATEGORIES: &[Category] = &[
-    Category {
-        name: "animales",
-        singular_form: "un animal",
-        emojis: &[
-            "🐕", "🐱", "🐰", "🐸", "🦊", "🐼", "🐨", "🦁", "🐵", "🐮", "🐷", "🐯", "🦒", "🐘",
-            "🦓",
-        ],
-    },
-    Category {
-        name: "comida",
-        singular_form: "comida",
-        emojis: &[
-            "🍕", "🍔", "🍎", "🍌", "🍇", "🥕", "🍅", "🥐", "🧀", "🥓", "🍗", "🍰", "🍪", "🍫",
-            "🥗",
-        ],
-    },
-    Category {
-        name: "deportes",
-        singular_form: "un deporte",
-        emojis: &[
-            "⚽", "🏀", "🎾", "🏈", "⚾", "🏐", "🏓", "🏸", "🥊", "🎱", "🎯", "🏹", "⛳", "🥅",
-            "🏆",
-        ],
-    },
-    Category {
-        name: "vehículos",
-        singular_form: "un vehículo",
-        emojis: &[
-            "🚗", "🚕", "🚙", "🚐", "🚛", "🚌", "🚎", "🏎️", "🚓", "🚑", "🚒", "🚚", "🛻", "🏍️",
-            "🚲",
-        ],
-    },
-    Category {
-        name: "fenómenos climáticos",
-        singular_form: "un fenómeno climático",
-        emojis: &[
-            "☀️", "🌙", "⭐", "☁️", "⛅", "🌧️", "⛈️", "🌩️", "❄️", "🌨️", "🌪️", "🌈", "⚡", "🔥",
-            "💧",
-        ],
-    },
-    Category {
-        name: "herramientas",
-        singular_form: "una herramienta",
-        emojis: &[
-            "🔨", "🔧", "🪚", "⚒️", "🛠️", "⛏️", "🪓", "🔩", "⚙️", "🪛", "📏", "📐", "✂️", "🔪",
-            "✏️",
-        ],
-    },
-    Category {
-        name: "plantas",
-        singular_form: "una planta",
-        emojis: &[
-            "🌳", "🌲", "🌴", "🌵", "🌿", "🍀", "🌺", "🌸", "🌼", "🌻", "🌷", "🥀", "💐", "🌱",
-            "🌾",
-        ],
-    },
-    Category {
-        name: "edificios",
-        singular_form: "un edificio",
-        emojis: &[
-            "🏠", "🏡", "🏢", "🏣", "🏤", "🏥", "🏦", "🏨", "🏩", "🏪", "🏫", "🏬", "🏭", "🏯",
-            "🏰",
-        ],
-    },
-];
-
-pub fn generate_category_challenge() -> (String, String, Vec<(String, String)>) {
-    let mut rng = rand::rng();
-
-    // Select two different categories
-    let main_category_idx = rng.random_range(0..CATEGORIES.len());
-    let mut different_category_idx = rng.random_range(0..CATEGORIES.len());
-    while different_category_idx == main_category_idx {
-        different_category_idx = rng.random_range(0..CATEGORIES.len());
-    }
-
-    let main_category = &CATEGORIES[main_category_idx];
-    let different_category = &CATEGORIES[different_category_idx];
-
-    // Select 4 emojis from main category
-    let mut main_emojis = Vec::new();
-    let mut used_indices = std::collections::HashSet::new();
-    while main_emojis.len() < 4 {
-        let idx = rng.random_range(0..main_category.emojis.len());
-        if !used_indices.contains(&idx) {
-            used_indices.insert(idx);
-            main_emojis.push(main_category.emojis[idx]);
-        }
-    }
-
-    // Select 1 emoji from different category
-    let different_emoji =
-        different_category.emojis[rng.random_range(0..different_category.emojis.len())];
-
-    // Create all 5 emojis and shuffle them
-    let mut all_emojis = main_emojis.clone();
-    all_emojis.push(different_emoji);
-    all_emojis.shuffle(&mut rng);
-
-    // Create the question
-    let question = format!("¿Cuál de estos NO es {}?", main_category.singular_form);
-
-    // Create buttons with UUIDs
-    let mut answers = Vec::new();
-    let mut correct_uuid = String::new();
-
-    for emoji in &all_emojis {
-        let uuid = Uuid::new_v4().to_string();
-        if *emoji == different_emoji {
-            correct_uuid = uuid.clone();
-        }
-        answers.push((emoji.to_string(), uuid));
-    }
-
-    (question, correct_uuid, answers)
+    pub whitelisted_bots: Vec<i64>,      // IDs de bots permitidos
+    pub notify_on_ban: bool,             // Notificar cuando se expulsa un bot
+    pub banned_bots_count: u64,          // Estadísticas de bots expulsados
+    pub challenge_topic_id: Option<i64>, // Tema del foro para desafíos y avisos
+    pub member_permissions: Option<ChatPermissions>, // Permisos tras superar el desafío, si no los del grupo
+    pub challenge_kinds: Vec<WeightedChallengeKind>, // Tipos de desafío activos y su peso
+    pub max_attempts: u32, // Respuestas que se pueden intentar antes de la expulsión
+    pub language: Language, // Idioma de los avisos y de las respuestas a comandos
+    pub custom_categories: Vec<CustomCategory>, // Categorías propias del desafío emoji
+    pub private_challenges: bool, // Desafío por privado mediante enlace en vez de en el grupo
+    pub penalty: Penalty,  // Qué pasa con quien falla el desafío
+    pub revoke_messages: bool, // Borrar sus mensajes al expulsarle
+    pub strikes: StrikeMode, // Sanción progresiva según los fallos anteriores
+    pub welcome: WelcomeConfig, // Mensaje para quien supera el desafío
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        BotConfig {
+            whitelisted_bots: Vec::new(),
+            notify_on_ban: true,
+            banned_bots_count: 0,
+            challenge_topic_id: None,
+            member_permissions: None,
+            challenge_kinds: Vec::new(),
+            max_attempts: 1,
+            language: Language::default(),
+            custom_categories: Vec::new(),
+            private_challenges: false,
+            penalty: Penalty::default(),
+            revoke_messages: true,
+            strikes: StrikeMode::default(),
+            welcome: WelcomeConfig::default(),
+        }
+    }
+}
+
+// Por chat, persisted under DATA_DIR and kept in sync by update_bot_config
+#[derive(Clone)]
+pub struct BotConfigState {
+    configs: Arc<Mutex<HashMap<i64, BotConfig>>>,
+    storage: Storage,
+}
+
+impl BotConfigState {
+    pub fn load(storage: Storage) -> Self {
+        let configs: HashMap<i64, BotConfig> = storage.load(BOT_CONFIGS_FILE);
+        debug!("Loaded bot configuration for {} chats", configs.len());
+        BotConfigState {
+            configs: Arc::new(Mutex::new(configs)),
+            storage,
+        }
+    }
+}
+
+// What a failed challenge costs the user
+pub struct Sanction {
+    pub penalty: Penalty,
+    pub revoke_messages: bool,
+    pub strike: Option<u32>, // Failures so far, when the chat counts them
+}
+
+impl Sanction {
+    pub async fn apply(
+        &self,
+        telegram_client: &Telegram,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<(), TelegramError> {
+        self.penalty
+            .apply(telegram_client, chat_id, user_id, self.revoke_messages)
+            .await
+    }
+
+    // Appended to the Matrix notifications
+    pub fn strike_note(&self) -> String {
+        self.strike
+            .map(|strike| format!(" (fallo nº {})", strike))
+            .unwrap_or_default()
+    }
+}
+
+// Picks the penalty of a failure: the chat's own, or the step of the ladder the
+// user has reached when the chat counts strikes. Failures are only recorded while
+// it does, so turning strikes on later does not punish what happened before
+pub async fn failure_sanction(
+    bot_config_state: &BotConfigState,
+    challenge_state: &ChallengeState,
+    chat_id: i64,
+    user_id: i64,
+) -> Sanction {
+    let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+    let strike = match config.strikes {
+        StrikeMode::Off => None,
+        mode => Some(
+            challenge_state
+                .record_strike(chat_id, user_id, mode == StrikeMode::Global)
+                .await,
+        ),
+    };
+    Sanction {
+        penalty: strike.map_or(config.penalty, Penalty::for_strike),
+        revoke_messages: config.revoke_messages,
+        strike,
+    }
+}
+
+// The fixed too-fast threshold, used until a chat has enough answers of its own
+pub fn min_response_time() -> Duration {
+    let seconds = env::var("MIN_RESPONSE_SECONDS")
+        .unwrap_or_else(|_| "1".to_string())
+        .parse::<u64>()
+        .unwrap_or(1);
+    Duration::from_secs(seconds)
+}
+
+pub fn cleanup_delay_seconds() -> u64 {
+    env::var("MESSAGE_CLEANUP_DELAY_SECONDS")
+        .unwrap_or_else(|_| "30".to_string())
+        .parse::<u64>()
+        .unwrap_or(30)
+}
+
+// Approves or declines a join request once its challenge is answered or expires
+#[allow(clippy::too_many_arguments)]
+pub async fn resolve_join_request(
+    telegram_client: &Arc<Telegram>,
+    chat_id: i64,
+    user_id: i64,
+    challenge: &ChallengeDetails,
+    approved: bool,
+    challenge_state: &ChallengeState,
+    open_observe_client: &Option<Arc<OpenObserve>>,
+    matrix_client: &Option<Arc<Matrix>>,
+) {
+    let Some(private_chat_id) = challenge.join_request_chat_id else {
+        return;
+    };
+    let group_name = challenge.chat_title.as_deref().unwrap_or("Unknown Group");
+    let chat = challenge
+        .chat_title
+        .as_deref()
+        .map(escape_html)
+        .unwrap_or_else(|| tr!(challenge.language, "challenge.default_chat_title"));
+
+    let text = if approved {
+        challenge_state
+            .mark_join_request_approved(chat_id, user_id)
+            .await;
+        if let Err(e) = telegram_client
+            .approve_chat_join_request(chat_id, user_id)
+            .await
+        {
+            error!(
+                "Failed to approve join request of user {} in chat {}: {}",
+                user_id, chat_id, e
+            );
+        }
+        tr!(challenge.language, "result.join_approved", chat = chat)
+    } else {
+        if let Err(e) = telegram_client
+            .decline_chat_join_request(chat_id, user_id)
+            .await
+        {
+            error!(
+                "Failed to decline join request of user {} in chat {}: {}",
+                user_id, chat_id, e
+            );
+        }
+        tr!(challenge.language, "result.join_declined", chat = chat)
+    };
+    // The challenge becomes the result; only if that fails is it sent apart
+    let _editing = challenge.edit_lock.lock().await;
+    let photo = challenge.puzzle.kind() == ChallengeKind::Image;
+    let edited = edit_challenge_message(
+        telegram_client,
+        private_chat_id,
+        challenge.challenge_message_id,
+        photo,
+        &text,
+        InlineKeyboardMarkup::default(),
+    )
+    .await;
+    if let Err(e) = edited {
+        warn!(
+            "Failed to show the join request result in message {} of user {}: {}",
+            challenge.challenge_message_id, user_id, e
+        );
+        if let Err(e) = telegram_client
+            .send_message(private_chat_id, None, &text)
+            .await
+        {
+            error!(
+                "Failed to send join request result to user {}: {}",
+                user_id, e
+            );
+        }
+        delete_messages_after_delay(
+            telegram_client.clone(),
+            private_chat_id,
+            vec![challenge.challenge_message_id],
+            cleanup_delay_seconds(),
+        )
+        .await;
+    }
+
+    // Send event to OpenObserve
+    if let Some(open_client) = open_observe_client {
+        let event = UserEvent {
+            user_id,
+            user_name: challenge.user_name.clone(),
+            group_id: chat_id,
+            group_name: group_name.to_string(),
+            challenge_completed: approved,
+            banned: false,
+            strike: None,
+        };
+        if let Err(e) = open_client.send_user_event(&event).await {
+            error!("Failed to send user event to OpenObserve: {:?}", e);
+        }
+    }
+
+    // Send message to Matrix
+    if let Some(matrix_client) = matrix_client {
+        let matrix_message = format!(
+            "el usuario {} con id {} {} el challenge y su solicitud de acceso al grupo {} con id {} fue {}",
+            challenge.user_name,
+            user_id,
+            if approved { "si superó" } else { "no superó" },
+            group_name,
+            chat_id,
+            if approved { "aprobada" } else { "rechazada" }
+        );
+        if let Err(e) = matrix_client.send_message(&matrix_message).await {
+            error!("Failed to send message to Matrix: {:?}", e);
+        }
+    }
 }
 
 // --- Timer Task ---
 
+#[allow(clippy::too_many_arguments)]
 pub async fn timer_task(
     telegram_client: Arc<Telegram>,
     chat_id: i64,
     user_id: i64,
     user_name: String,
     chat_title: Option<String>,
-    _challenge_message_id: u64,
-    rx: oneshot::Receiver<()>, // Channel to receive signal for completion
+    challenge_duration: Duration, // Remaining time, shorter than the full duration when resumed
+    rx: oneshot::Receiver<()>,    // Channel to receive signal for completion
     state: ChallengeState,
+    bot_config_state: BotConfigState,
     open_observe_client: Option<Arc<OpenObserve>>,
     matrix_client: Option<Arc<Matrix>>,
 ) {
-    let challenge_duration_minutes = env::var("CHALLENGE_DURATION_MINUTES")
-        .unwrap_or_else(|_| "2".to_string())
+    let timer = sleep(challenge_duration);
+    tokio::pin!(timer);
+    tokio::pin!(rx);
+    let mut countdown = countdown_interval();
+    loop {
+        tokio::select! {
+            _ = &mut timer => break,
+            _ = &mut rx => {
+                // Challenge completed by user selecting a button
+                debug!("Challenge completed by user {} in chat {}", user_id, chat_id);
+                // The completion logic is handled in the callback query handler.
+                // This branch is reached when the callback handler successfully signals completion.
+                return;
+            }
+            _ = next_countdown_tick(&mut countdown) => {
+                refresh_countdown(&telegram_client, &state, chat_id, user_id).await;
+            }
+        }
+    }
+
+    // Timer expired
+    debug!(
+        "Challenge timer expired for user {} in chat {}",
+        user_id, chat_id
+    );
+    // The group may have become a supergroup while the challenge was running
+    let chat_id = telegram_client.resolve_chat_id(chat_id);
+    // An answer that got here first has already taken it
+    let claimed = ChallengeState::remove_challenge(&mut *state.lock().await, chat_id, user_id);
+    let Some(challenge) = claimed else {
+        return;
+    };
+
+    if challenge.join_request_chat_id.is_some() {
+        debug!(
+            "User {} did not respond in time. Declining join request.",
+            user_id
+        );
+        resolve_join_request(
+            &telegram_client,
+            chat_id,
+            user_id,
+            &challenge,
+            false,
+            &state,
+            &open_observe_client,
+            &matrix_client,
+        )
+        .await;
+    } else {
+        let sanction = failure_sanction(&bot_config_state, &state, chat_id, user_id).await;
+        let penalty = sanction.penalty;
+        debug!(
+            "User {} did not respond in time. Penalty: {:?} (strike {:?})",
+            user_id, penalty, sanction.strike
+        );
+        if let Err(e) = sanction.apply(&telegram_client, chat_id, user_id).await {
+            error!("Failed to apply penalty to user {}: {}", user_id, e);
+        } else {
+            let outcome = if penalty.removes_member() {
+                tr!(
+                    challenge.language,
+                    "result.timed_out",
+                    name = escape_html(&user_name)
+                )
+            } else {
+                tr!(
+                    challenge.language,
+                    "result.timed_out_muted",
+                    name = escape_html(&user_name)
+                )
+            };
+            announce_outcome(&telegram_client, chat_id, &challenge, &outcome).await;
+
+            // Send event to OpenObserve
+            if let Some(open_client) = &open_observe_client {
+                let event = UserEvent {
+                    user_id,
+                    user_name: user_name.clone(),
+                    group_id: chat_id,
+                    group_name: chat_title.as_deref().unwrap_or("Unknown Group").to_string(),
+                    challenge_completed: false,
+                    banned: penalty.removes_member(),
+                    strike: sanction.strike,
+                };
+                if let Err(e) = open_client.send_user_event(&event).await {
+                    error!("Failed to send user event to OpenObserve: {:?}", e);
+                }
+            }
+
+            // Send message to Matrix
+            if let Some(matrix_client) = &matrix_client {
+                let matrix_message = format!(
+                    "el usuario {} con id {} no superó el challenge y fue {} del grupo {} con id {}{}",
+                    user_name,
+                    user_id,
+                    penalty.log_description(),
+                    chat_title.as_deref().unwrap_or("Unknown Group"),
+                    chat_id,
+                    sanction.strike_note()
+                );
+                if let Err(e) = matrix_client.send_message(&matrix_message).await {
+                    error!("Failed to send message to Matrix: {:?}", e);
+                }
+            }
+        }
+    }
+
+    state.sync().await;
+}
+
+// How often the time left of a pending challenge is rewritten; 0 turns it off
+fn countdown_interval() -> Option<Interval> {
+    let seconds = env::var("COUNTDOWN_UPDATE_SECONDS")
+        .unwrap_or_else(|_| "30".to_string())
         .parse::<u64>()
-        .unwrap_or(2);
-    let challenge_duration = Duration::from_secs(challenge_duration_minutes * 60);
+        .unwrap_or(30);
+    if seconds == 0 {
+        return None;
+    }
+    let period = Duration::from_secs(seconds);
+    let mut interval = interval_at(Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    Some(interval)
+}
 
-    let timer = sleep(challenge_duration);
-    tokio::select! {
-        _ = timer => {
-            // Timer expired
-            debug!("Challenge timer expired for user {} in chat {}", user_id, chat_id);
-            let mut state_guard = state.lock().await;
-
-            if let Some(user_challenges) = state_guard.get_mut(&chat_id) {
-                if let Some(challenge) = user_challenges.get(&user_id) {
-
-                    debug!("User {} did not respond in time. Banning.", user_id);
-                    // Ban user
-                    if let Err(e) = telegram_client.ban_chat_member(chat_id, user_id).await {
-                        error!("Failed to ban user {}: {}", user_id, e);
-                    } else {
-                        let mut messages_to_delete = vec![challenge.challenge_message_id];
-
-                        // Send a notification and collect message ID
-                        if let Ok(msg_id) = telegram_client.send_message(chat_id, &format!("El usuario {} fue expulsado por no completar el desafío.", user_name)).await {
-                            messages_to_delete.push(msg_id);
-                        }
-
-                        // Send event to OpenObserve
-                        if let Some(open_client) = &open_observe_client {
-                            let event = UserEvent {
-                                user_id,
-                                user_name: user_name.clone(),
-                                group_id: chat_id,
-                                group_name: chat_title.as_deref().unwrap_or("Unknown Group").to_string(),
-                                challenge_completed: false,
-                                banned: true,
-                            };
-                            if let Err(e) = open_client.send_user_event(&event).await {
-                                error!("Failed to send user event to OpenObserve: {:?}", e);
-                            }
-                        }
-
-                        // Send message to Matrix
-                        if let Some(matrix_client) = &matrix_client {
-                            let matrix_message = format!(
-                                "el usuario {} con id {} no superó el challenge y fue baneado del grupo {} con id {}",
-                                user_name,
-                                user_id,
-                                chat_title.as_deref().unwrap_or("Unknown Group"),
-                                chat_id
-                            );
-                            if let Err(e) = matrix_client.send_message(&matrix_message).await {
-                                error!("Failed to send message to Matrix: {:?}", e);
-                            }
-                        }
-
-                        // Programar eliminación de mensajes después del tiempo configurado
-                        let cleanup_delay = env::var("MESSAGE_CLEANUP_DELAY_SECONDS")
-                            .unwrap_or_else(|_| "30".to_string())
-                            .parse::<u64>()
-                            .unwrap_or(30);
-
-                        delete_messages_after_delay(
-                            telegram_client.clone(),
-                            chat_id,
-                            messages_to_delete,
-                            cleanup_delay,
-                        ).await;
-                    }
-
-                    // Remove from state
-                    user_challenges.remove(&user_id);
-                    // Clean up chat entry if no more users
-                    if user_challenges.is_empty() {
-                        state_guard.remove(&chat_id);
-                    }
+// Never resolves when the countdown is off
+async fn next_countdown_tick(countdown: &mut Option<Interval>) {
+    match countdown {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Re-arms the timers of the challenges that were pending when the bot stopped.
+// Those whose deadline already passed get a zero timer, so they take the usual
+// timeout path and the user is banned instead of staying restricted forever.
+pub async fn resume_pending_challenges(
+    telegram_client: Arc<Telegram>,
+    challenge_state: &ChallengeState,
+    bot_config_state: &BotConfigState,
+    open_observe_client: Option<Arc<OpenObserve>>,
+    matrix_client: Option<Arc<Matrix>>,
+) {
+    let pending: Vec<PendingChallenge> = challenge_state.storage.load(PENDING_CHALLENGES_FILE);
+    if pending.is_empty() {
+        return;
+    }
+    info!("Resuming {} pending challenges", pending.len());
+
+    let now = unix_now();
+    let mut state_guard = challenge_state.lock().await;
+    let mut timers = Vec::with_capacity(pending.len());
+    for challenge in pending {
+        let remaining = Duration::from_secs(challenge.deadline.saturating_sub(now));
+        let elapsed = Duration::from_secs(now.saturating_sub(challenge.started_at));
+        debug!(
+            "Resuming challenge for user {} in chat {} with {:?} left",
+            challenge.user_id, challenge.chat_id, remaining
+        );
+
+        let (tx, rx) = oneshot::channel();
+        state_guard.entry(challenge.chat_id).or_default().insert(
+            challenge.user_id,
+            ChallengeDetails {
+                puzzle: challenge.kind.restore(challenge.correct_answer),
+                attempts_left: challenge.attempts_left,
+                challenge_message_id: challenge.challenge_message_id,
+                start_time: Instant::now()
+                    .checked_sub(elapsed)
+                    .unwrap_or_else(Instant::now),
+                user_name: challenge.user_name.clone(),
+                chat_title: challenge.chat_title.clone(),
+                started_at: challenge.started_at,
+                deadline: challenge.deadline,
+                join_request_chat_id: challenge.join_request_chat_id,
+                message_thread_id: challenge.message_thread_id,
+                language: challenge.language,
+                deep_link: challenge.deep_link,
+                message: challenge.message,
+                edit_lock: Arc::default(),
+                tx,
+            },
+        );
+
+        timers.push((
+            challenge.chat_id,
+            challenge.user_id,
+            challenge.user_name,
+            challenge.chat_title,
+            remaining,
+            rx,
+        ));
+    }
+    drop(state_guard);
+
+    // Expired ones time out right away, which needs the lock
+    for (chat_id, user_id, user_name, chat_title, remaining, rx) in timers {
+        tokio::spawn(timer_task(
+            Arc::clone(&telegram_client),
+            chat_id,
+            user_id,
+            user_name,
+            chat_title,
+            remaining,
+            rx,
+            challenge_state.clone(),
+            bot_config_state.clone(),
+            open_observe_client.clone(),
+            matrix_client.clone(),
+        ));
+    }
+}
+
+// Reacts to changes of the bot's own membership (my_chat_member updates)
+pub async fn process_my_chat_member(
+    telegram_client: Arc<Telegram>,
+    update: &ChatMemberUpdated,
+    challenge_state: &ChallengeState,
+    bot_config_state: &BotConfigState,
+) {
+    let chat_id = update.chat.id;
+    if update.chat.chat_type != "group" && update.chat.chat_type != "supergroup" {
+        return;
+    }
+    let old_status = update.old_chat_member.status.as_str();
+    let new_status = update.new_chat_member.status.as_str();
+    info!(
+        "Bot membership changed in chat {} ({:?}): {} -> {}",
+        chat_id, update.chat.title, old_status, new_status
+    );
+
+    let language = chat_language(bot_config_state, chat_id).await;
+    let was_admin = old_status == "administrator" || old_status == "creator";
+    let text = match new_status {
+        "member" if was_admin => Some(tr!(language, "membership.demoted")),
+        "member" => Some(tr!(language, "membership.added")),
+        "administrator" if !was_admin => {
+            let member = &update.new_chat_member;
+            if member.can_restrict_members == Some(false)
+                || member.can_delete_messages == Some(false)
+            {
+                Some(tr!(language, "membership.missing_rights"))
+            } else {
+                Some(tr!(language, "membership.ready"))
+            }
+        }
+        "left" | "kicked" => {
+            // Nothing can be enforced anymore, forget the challenges of this chat
+            let mut state_guard = challenge_state.lock().await;
+            if let Some(chat_challenges) = state_guard.remove(&chat_id) {
+                debug!(
+                    "Dropping {} pending challenges of chat {}",
+                    chat_challenges.len(),
+                    chat_id
+                );
+                for (_, challenge) in chat_challenges {
+                    let _ = challenge.tx.send(());
                 }
+                challenge_state.persist(&state_guard).await;
             }
-        },
-        _ = rx => {
-            // Challenge completed by user selecting a button
-            debug!("Challenge completed by user {} in chat {}", user_id, chat_id);
-            // The completion logic is handled in the callback query handler.
-            // This branch is reached when the callback handler successfully signals completion.
+            None
+        }
+        _ => None,
+    };
+
+    if let Some(text) = text {
+        if let Err(e) = telegram_client.send_message(chat_id, None, &text).await {
+            error!(
+                "Failed to send membership message to chat {}: {}",
+                chat_id, e
+            );
         }
     }
 }
 
 // Function to process new members (both from chat_member updates and new_chat_members)
+#[allow(clippy::too_many_arguments)]
 pub async fn process_new_member(
     telegram_client: Arc<Telegram>,
     chat_id: i64,
-    user_id: i64,
-    first_name: &str,
+    user: &User,
     chat_title: Option<String>,
+    message_thread_id: Option<i64>,
     challenge_state: &ChallengeState,
+    bot_config_state: &BotConfigState,
     open_observe_client: Option<Arc<OpenObserve>>,
     matrix_client: Option<Arc<Matrix>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let user_id = user.id;
+    let first_name = user.first_name.as_str();
     debug!(
         "Processing new member: User ID {} in chat {}",
         user_id, chat_id
     );
 
-    if telegram_client
-        .restrict_chat_member(chat_id, user_id)
+    // The same join arrives both as a service message and as a chat_member update
+    let already_challenged = challenge_state
+        .lock()
+        .await
+        .get(&chat_id)
+        .is_some_and(|chat_challenges| chat_challenges.contains_key(&user_id));
+    if already_challenged {
+        debug!(
+            "User {} already has a pending challenge in chat {}, skipping",
+            user_id, chat_id
+        );
+        return Ok(());
+    }
+    if challenge_state
+        .was_join_request_approved(chat_id, user_id)
         .await
-        .is_err()
     {
-        error!(
-            "Failed to restrict chat member {} in chat {}",
+        debug!(
+            "User {} passed the join request challenge of chat {}, skipping",
             user_id, chat_id
         );
-        return Err("Failed to restrict member".into());
+        return Ok(());
     }
-    debug!("Permissions restricted for user {}", user_id);
 
-    let (problem_text, correct_uuid, answer_options) = generate_category_challenge();
-
-    let mut keyboard_buttons: Vec<InlineKeyboardButton> = Vec::new();
-    for (emoji_text, uuid) in &answer_options {
-        keyboard_buttons.push(InlineKeyboardButton {
-            text: emoji_text.clone(),
-            url: None,
-            callback_data: Some(uuid.clone()),
-        });
+    if let Err(e) = telegram_client
+        .restrict_chat_member(chat_id, user_id, &ChatPermissions::none())
+        .await
+    {
+        error!(
+            "Failed to restrict chat member {} in chat {}: {}",
+            user_id, chat_id, e
+        );
+        return Err(e.into());
     }
+    debug!("Permissions restricted for user {}", user_id);
 
-    let mut inline_keyboard = Vec::new();
-    let mut row = Vec::new();
-    for button in keyboard_buttons {
-        row.push(button);
-    }
-    if !row.is_empty() {
-        inline_keyboard.push(row);
-    }
-    let markup = InlineKeyboardMarkup { inline_keyboard };
+    // The challenge speaks the user's language, the chat's when there is no catalog for it
+    let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+    let language = Language::for_user(user.language_code.as_deref(), config.language);
+    let puzzle =
+        pick_challenge_kind(&config.challenge_kinds).generate(language, &config.custom_categories);
+    let max_attempts = config.max_attempts.max(1);
 
-    let challenge_duration_minutes = env::var("CHALLENGE_DURATION_MINUTES")
-        .unwrap_or_else(|_| "2".to_string())
-        .parse::<u64>()
-        .unwrap_or(2);
+    let duration = challenge_duration();
+    let started_at = unix_now();
+    let deadline = started_at + duration.as_secs();
 
-    let challenge_text = format!(
-        "¡Bienvenido, <b>{}</b>!\nPara confirmar que eres un ser humano, supera el desafío,\n{}\n\nTienes {} minutos.",
-        first_name,
-        problem_text,
-        challenge_duration_minutes
-    );
+    // In private mode the group only gets a link; the challenge is sent when it is opened
+    let deep_link_token = config
+        .private_challenges
+        .then(|| Uuid::new_v4().simple().to_string());
+    let sent = match &deep_link_token {
+        Some(token) => {
+            send_deep_link_prompt(
+                &telegram_client,
+                chat_id,
+                message_thread_id,
+                language,
+                first_name,
+                deadline,
+                token,
+            )
+            .await
+        }
+        None => {
+            let message = ChallengeMessage::new(
+                tr!(
+                    language,
+                    "challenge.welcome",
+                    name = escape_html(first_name),
+                    question = puzzle.question(),
+                ),
+                puzzle.as_ref(),
+            );
+            send_challenge(
+                &telegram_client,
+                chat_id,
+                message_thread_id,
+                &with_time_left(language, &message.body, deadline),
+                puzzle.as_ref(),
+            )
+            .await
+            .map(|message_id| (message_id, message))
+        }
+    };
 
-    match telegram_client
-        .send_message_with_keyboard(chat_id, &challenge_text, markup)
-        .await
-    {
-        Ok(message_id) => {
+    match sent {
+        Ok((message_id, message)) => {
             debug!(
                 "Challenge message sent to user {} in chat {}: Message ID {}",
                 user_id, chat_id, message_id
@@ -372,9 +1686,24 @@ pub async fn process_new_member(
 
             let (tx, rx) = oneshot::channel();
             let challenge_details = ChallengeDetails {
-                correct_answer: correct_uuid,
+                attempts_left: max_attempts,
+                puzzle,
                 challenge_message_id: message_id,
                 start_time: Instant::now(),
+                user_name: first_name.to_string(),
+                chat_title: chat_title.clone(),
+                started_at,
+                deadline,
+                join_request_chat_id: None,
+                message_thread_id,
+                language,
+                deep_link: deep_link_token.map(|token| DeepLink {
+                    token,
+                    prompt_message_id: message_id,
+                    private_chat_id: None,
+                }),
+                message: Some(message),
+                edit_lock: Arc::default(),
                 tx,
             };
 
@@ -383,9 +1712,11 @@ pub async fn process_new_member(
                 .entry(chat_id)
                 .or_default()
                 .insert(user_id, challenge_details);
+            challenge_state.persist(&state_guard).await;
             drop(state_guard);
 
-            let state_clone = Arc::clone(&challenge_state);
+            let state_clone = challenge_state.clone();
+            let bot_config_clone = bot_config_state.clone();
             let telegram_client_clone = Arc::clone(&telegram_client);
             let first_name_clone = first_name.to_string();
             let chat_title_clone = chat_title.clone();
@@ -399,9 +1730,10 @@ pub async fn process_new_member(
                     user_id,
                     first_name_clone,
                     chat_title_clone,
-                    message_id,
+                    duration,
                     rx,
                     state_clone,
+                    bot_config_clone,
                     open_observe_clone,
                     matrix_clone,
                 )
@@ -415,8 +1747,7 @@ pub async fn process_new_member(
                 "Failed to send challenge message for user {}: {}",
                 user_id, e
             );
-            if telegram_client
-                .unrestrict_chat_member(chat_id, user_id)
+            if restore_member_permissions(&telegram_client, bot_config_state, chat_id, user_id)
                 .await
                 .is_err()
             {
@@ -425,49 +1756,154 @@ pub async fn process_new_member(
                     user_id, e
                 );
             }
-            Err(e)
+            Err(e.into())
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// Challenges a user asking to join a group with "approve new members" enabled.
+// The challenge is sent to the user's private chat, so the group never sees them
+// unless they pass; on failure or timeout the request is declined.
+pub async fn process_join_request(
+    telegram_client: Arc<Telegram>,
+    request: &ChatJoinRequest,
+    challenge_state: &ChallengeState,
+    bot_config_state: &BotConfigState,
+    open_observe_client: Option<Arc<OpenObserve>>,
+    matrix_client: Option<Arc<Matrix>>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let chat_id = request.chat.id;
+    let user_id = request.from.id;
+    debug!(
+        "Processing join request: User ID {} in chat {}",
+        user_id, chat_id
+    );
 
-    #[test]
-    fn test_generate_category_challenge() {
-        println!("🧪 Probando el sistema de categorización...\n");
+    let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+    let language = Language::for_user(request.from.language_code.as_deref(), config.language);
+    let puzzle =
+        pick_challenge_kind(&config.challenge_kinds).generate(language, &config.custom_categories);
+    let max_attempts = config.max_attempts.max(1);
+    let duration = challenge_duration();
+    let started_at = unix_now();
+    let deadline = started_at + duration.as_secs();
 
-        for i in 1..=5 {
-            let (question, correct_uuid, options) = generate_category_challenge();
+    let chat = request
+        .chat
+        .title
+        .as_deref()
+        .map(escape_html)
+        .unwrap_or_else(|| tr!(language, "challenge.default_chat_title"));
+    let message = ChallengeMessage::new(
+        tr!(
+            language,
+            "challenge.join_request",
+            name = escape_html(&request.from.first_name),
+            chat = chat,
+            question = puzzle.question(),
+        ),
+        puzzle.as_ref(),
+    );
 
-            println!("--- Desafío {} ---", i);
-            println!("❓ {}", question);
-            println!("🔗 UUID correcto: {}", correct_uuid);
-            println!("📋 Opciones:");
+    // If the user cannot be reached the request stays pending for the admins
+    let message_id = send_challenge(
+        &telegram_client,
+        request.user_chat_id,
+        None,
+        &with_time_left(language, &message.body, deadline),
+        puzzle.as_ref(),
+    )
+    .await?;
+    debug!(
+        "Join request challenge sent to user {} for chat {}: Message ID {}",
+        user_id, chat_id, message_id
+    );
 
-            // Verify that we have 5 options
-            assert_eq!(options.len(), 5);
+    let (tx, rx) = oneshot::channel();
+    let challenge_details = ChallengeDetails {
+        attempts_left: max_attempts,
+        puzzle,
+        challenge_message_id: message_id,
+        start_time: Instant::now(),
+        user_name: request.from.first_name.clone(),
+        chat_title: request.chat.title.clone(),
+        started_at,
+        deadline,
+        join_request_chat_id: Some(request.user_chat_id),
+        message_thread_id: None,
+        language,
+        deep_link: None,
+        message: Some(message),
+        edit_lock: Arc::default(),
+        tx,
+    };
 
-            // Verify that one of the options has the correct UUID
-            let has_correct_uuid = options.iter().any(|(_, uuid)| *uuid == correct_uuid);
-            assert!(
-                has_correct_uuid,
-                "None of the options matches the correct UUID"
-            );
+    let mut state_guard = challenge_state.lock().await;
+    state_guard
+        .entry(chat_id)
+        .or_default()
+        .insert(user_id, challenge_details);
+    challenge_state.persist(&state_guard).await;
+    drop(state_guard);
 
-            // Verify question format
-            assert!(
-                question.contains("¿Cuál de estos NO es "),
-                "Question doesn't have expected format"
-            );
+    tokio::spawn(timer_task(
+        Arc::clone(&telegram_client),
+        chat_id,
+        user_id,
+        request.from.first_name.clone(),
+        request.chat.title.clone(),
+        duration,
+        rx,
+        challenge_state.clone(),
+        bot_config_state.clone(),
+        open_observe_client,
+        matrix_client,
+    ));
 
-            for (j, (emoji, uuid)) in options.iter().enumerate() {
-                let marker = if *uuid == correct_uuid { "✅" } else { "❌" };
-                println!("   {}. {} {} ({})", j + 1, emoji, marker, uuid);
-            }
+    Ok(())
+}
 
-            println!();
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_private_challenge_chat() {
+        let (tx, _rx) = oneshot::channel();
+        let mut challenges: Challenges = HashMap::new();
+        challenges.entry(-100).or_default().insert(
+            42,
+            ChallengeDetails {
+                puzzle: ChallengeKind::Emoji.restore("uuid".to_string()),
+                attempts_left: 1,
+                challenge_message_id: 7,
+                start_time: Instant::now(),
+                user_name: "Juan".to_string(),
+                chat_title: None,
+                started_at: 0,
+                deadline: 0,
+                join_request_chat_id: Some(42),
+                message_thread_id: None,
+                language: Language::Es,
+                deep_link: None,
+                message: None,
+                edit_lock: Arc::default(),
+                tx,
+            },
+        );
+
+        assert_eq!(
+            ChallengeState::find_private_challenge_chat(&challenges, 42, 42, 7),
+            Some(-100)
+        );
+        // Another message, another user or a group challenge must not match
+        assert_eq!(
+            ChallengeState::find_private_challenge_chat(&challenges, 42, 42, 8),
+            None
+        );
+        assert_eq!(
+            ChallengeState::find_private_challenge_chat(&challenges, 43, 43, 7),
+            None
+        );
     }
 }
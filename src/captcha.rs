@@ -0,0 +1,184 @@
+use image::{ImageFormat, Rgb, RgbImage};
+use rand::prelude::*;
+use std::f32::consts::PI;
+use std::io::Cursor;
+
+// --- Distorted code images for the image challenge ---
+//
+// Glyphs come from a built-in 5x7 bitmap font, so no font files are needed. Every
+// character gets its own size, rotation, shear and colour, the whole picture is
+// then bent by two sine waves and crossed by noise lines and dots.
+
+const WIDTH: u32 = 280;
+const HEIGHT: u32 = 100;
+
+// Characters that stay readable once distorted (no 0/O, 1/I, B/8 or S/5 pairs)
+pub const CAPTCHA_ALPHABET: &str = "2346789ACEFHKLMNPRTUWXY";
+
+// Rows of 5 pixels, most significant bit on the left
+fn glyph(c: char) -> Option<[u8; 7]> {
+    let rows = match c {
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x0A, 0x04, 0x04, 0x04, 0x04],
+        _ => return None,
+    };
+    Some(rows)
+}
+
+fn glyph_pixel(rows: &[u8; 7], x: usize, y: usize) -> bool {
+    rows[y] & (0x10 >> x) != 0
+}
+
+pub fn random_code(len: usize) -> String {
+    let alphabet: Vec<char> = CAPTCHA_ALPHABET.chars().collect();
+    let mut rng = rand::rng();
+    (0..len)
+        .map(|_| *alphabet.choose(&mut rng).unwrap())
+        .collect()
+}
+
+fn dark_colour(rng: &mut impl Rng) -> Rgb<u8> {
+    Rgb([
+        rng.random_range(0..110),
+        rng.random_range(0..110),
+        rng.random_range(0..110),
+    ])
+}
+
+// Renders `code` as a distorted PNG; characters outside CAPTCHA_ALPHABET are skipped
+pub fn render_code(code: &str) -> Result<Vec<u8>, image::ImageError> {
+    let mut rng = rand::rng();
+    let background = Rgb([
+        rng.random_range(215..=255),
+        rng.random_range(215..=255),
+        rng.random_range(215..=255),
+    ]);
+    let mut text = RgbImage::from_pixel(WIDTH, HEIGHT, background);
+
+    let glyphs: Vec<[u8; 7]> = code.chars().filter_map(glyph).collect();
+    let cell_width = (WIDTH as f32 - 40.0) / glyphs.len().max(1) as f32;
+    for (i, rows) in glyphs.iter().enumerate() {
+        let scale: f32 = rng.random_range(7.0..9.0);
+        let angle: f32 = rng.random_range(-0.2..0.2);
+        let shear: f32 = rng.random_range(-0.2..0.2);
+        let colour = dark_colour(&mut rng);
+        let centre_x = 20.0 + (i as f32 + 0.5) * cell_width + rng.random_range(-6.0..6.0);
+        let centre_y = HEIGHT as f32 / 2.0 + rng.random_range(-8.0..8.0);
+        let (glyph_width, glyph_height) = (5.0 * scale, 7.0 * scale);
+        let reach = glyph_width.hypot(glyph_height) / 2.0 + 2.0;
+        let (sin, cos) = angle.sin_cos();
+
+        // Walk the destination box and map every pixel back into the glyph
+        let (min_x, max_x) = (
+            (centre_x - reach).max(0.0),
+            (centre_x + reach).min(WIDTH as f32 - 1.0),
+        );
+        let (min_y, max_y) = (
+            (centre_y - reach).max(0.0),
+            (centre_y + reach).min(HEIGHT as f32 - 1.0),
+        );
+        for y in min_y as u32..=max_y as u32 {
+            for x in min_x as u32..=max_x as u32 {
+                let (dx, dy) = (x as f32 - centre_x, y as f32 - centre_y);
+                let v = -dx * sin + dy * cos;
+                let u = dx * cos + dy * sin - shear * v;
+                let gx = (u + glyph_width / 2.0) / scale;
+                let gy = (v + glyph_height / 2.0) / scale;
+                if (0.0..5.0).contains(&gx)
+                    && (0.0..7.0).contains(&gy)
+                    && glyph_pixel(rows, gx as usize, gy as usize)
+                {
+                    text.put_pixel(x, y, colour);
+                }
+            }
+        }
+    }
+
+    // Bend everything with a horizontal and a vertical wave
+    let (amplitude_x, period_x, phase_x) = (
+        rng.random_range(2.0..4.0f32),
+        rng.random_range(70.0..110.0f32),
+        rng.random_range(0.0..2.0 * PI),
+    );
+    let (amplitude_y, period_y, phase_y) = (
+        rng.random_range(2.0..5.0f32),
+        rng.random_range(60.0..120.0f32),
+        rng.random_range(0.0..2.0 * PI),
+    );
+    let mut image = RgbImage::from_fn(WIDTH, HEIGHT, |x, y| {
+        let sx = x as f32 + amplitude_x * (2.0 * PI * y as f32 / period_x + phase_x).sin();
+        let sy = y as f32 + amplitude_y * (2.0 * PI * x as f32 / period_y + phase_y).sin();
+        let sx = sx.clamp(0.0, WIDTH as f32 - 1.0) as u32;
+        let sy = sy.clamp(0.0, HEIGHT as f32 - 1.0) as u32;
+        *text.get_pixel(sx, sy)
+    });
+
+    // Noise lines in the colours of the text, so they cannot be filtered out by colour
+    for _ in 0..rng.random_range(3..5) {
+        let colour = dark_colour(&mut rng);
+        let (x0, y0) = (
+            rng.random_range(0.0..WIDTH as f32),
+            rng.random_range(0.0..HEIGHT as f32),
+        );
+        let (x1, y1) = (
+            rng.random_range(0.0..WIDTH as f32),
+            rng.random_range(0.0..HEIGHT as f32),
+        );
+        let steps = (x1 - x0).abs().max((y1 - y0).abs()) as u32;
+        for step in 0..=steps {
+            let t = step as f32 / steps.max(1) as f32;
+            let (x, y) = ((x0 + (x1 - x0) * t) as u32, (y0 + (y1 - y0) * t) as u32);
+            for (px, py) in [(x, y), (x, y + 1)] {
+                if px < WIDTH && py < HEIGHT {
+                    image.put_pixel(px, py, colour);
+                }
+            }
+        }
+    }
+    for _ in 0..(WIDTH * HEIGHT / 20) {
+        let (x, y) = (rng.random_range(0..WIDTH), rng.random_range(0..HEIGHT));
+        let shade = rng.random_range(0..=255);
+        image.put_pixel(x, y, Rgb([shade, shade, shade]));
+    }
+
+    let mut png = Cursor::new(Vec::new());
+    image.write_to(&mut png, ImageFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_code_produces_a_png() {
+        assert!(CAPTCHA_ALPHABET.chars().all(|c| glyph(c).is_some()));
+
+        let code = random_code(5);
+        assert_eq!(code.chars().count(), 5);
+        let png = render_code(&code).unwrap();
+        let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (WIDTH, HEIGHT));
+    }
+}
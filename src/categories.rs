@@ -0,0 +1,270 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::info;
+
+use crate::i18n::Language;
+
+// --- Emoji challenge categories ---
+//
+// The dataset lives in assets/categories.toml, embedded at build time. Setting
+// CATEGORIES_FILE loads another file instead; either way it is validated when the
+// bot starts, and an invalid dataset stops it before it takes any update.
+
+const DEFAULT_CATEGORIES: &str = include_str!("../assets/categories.toml");
+
+pub const MIN_CATEGORY_ITEMS: usize = 4;
+const MAX_NAME_CHARS: usize = 40;
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct Category {
+    pub key: String,
+    // Singular form for the question: "¿Cuál de estos NO es {name}?"
+    names: HashMap<Language, String>,
+    pub emojis: Vec<String>,
+}
+
+impl Category {
+    pub fn name(&self, language: Language) -> &str {
+        // validate() makes sure every language has one
+        &self.names[&language]
+    }
+}
+
+#[derive(Deserialize)]
+struct Dataset {
+    category: Vec<Category>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CategoriesError {
+    Unreadable(String),
+    Malformed(String),
+    TooFewCategories,
+    RepeatedKey(String),
+    TooFewItems(String),
+    RepeatedItem {
+        item: String,
+        first: String,
+        second: String,
+    },
+    MissingName {
+        key: String,
+        language: Language,
+    },
+    MalformedName {
+        key: String,
+        language: Language,
+        name: String,
+    },
+}
+
+impl fmt::Display for CategoriesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CategoriesError::Unreadable(e) => write!(f, "cannot read the categories file: {}", e),
+            CategoriesError::Malformed(e) => write!(f, "invalid categories file: {}", e),
+            CategoriesError::TooFewCategories => write!(f, "at least 2 categories are needed"),
+            CategoriesError::RepeatedKey(key) => write!(f, "category {} is defined twice", key),
+            CategoriesError::TooFewItems(key) => write!(
+                f,
+                "category {} needs at least {} emojis",
+                key, MIN_CATEGORY_ITEMS
+            ),
+            CategoriesError::RepeatedItem {
+                item,
+                first,
+                second,
+            } if first == second => write!(f, "{} is twice in category {}", item, first),
+            CategoriesError::RepeatedItem {
+                item,
+                first,
+                second,
+            } => write!(f, "{} is in both {} and {}", item, first, second),
+            CategoriesError::MissingName { key, language } => {
+                write!(f, "category {} has no name in {}", key, language.code())
+            }
+            CategoriesError::MalformedName {
+                key,
+                language,
+                name,
+            } => write!(
+                f,
+                "category {} has a malformed {} name: {:?}",
+                key,
+                language.code(),
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CategoriesError {}
+
+// Items compare ignoring case and emoji variation selectors, so "☀" clashes with "☀️"
+pub fn item_key(item: &str) -> String {
+    item.chars()
+        .filter(|c| *c != '\u{fe0f}')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// A singular form goes in the middle of a question sent as HTML, so it must be a
+// short single line without markup, placeholders or its own punctuation
+pub fn is_well_formed_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.trim() == name
+        && name.chars().count() <= MAX_NAME_CHARS
+        && !name.contains(['\n', '<', '>', '&', '{', '}'])
+        && !name.ends_with(['?', '!', '.', ':', ','])
+}
+
+fn validate(categories: &[Category]) -> Result<(), CategoriesError> {
+    if categories.len() < 2 {
+        return Err(CategoriesError::TooFewCategories);
+    }
+
+    let mut keys = HashSet::new();
+    let mut owners: HashMap<String, &str> = HashMap::new();
+    for category in categories {
+        if !keys.insert(category.key.as_str()) {
+            return Err(CategoriesError::RepeatedKey(category.key.clone()));
+        }
+        if category.emojis.len() < MIN_CATEGORY_ITEMS {
+            return Err(CategoriesError::TooFewItems(category.key.clone()));
+        }
+        for &language in Language::ALL {
+            let Some(name) = category.names.get(&language) else {
+                return Err(CategoriesError::MissingName {
+                    key: category.key.clone(),
+                    language,
+                });
+            };
+            if !is_well_formed_name(name) {
+                return Err(CategoriesError::MalformedName {
+                    key: category.key.clone(),
+                    language,
+                    name: name.clone(),
+                });
+            }
+        }
+        for emoji in &category.emojis {
+            if let Some(first) = owners.insert(item_key(emoji), &category.key) {
+                return Err(CategoriesError::RepeatedItem {
+                    item: emoji.clone(),
+                    first: first.to_string(),
+                    second: category.key.clone(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse(source: &str) -> Result<Vec<Category>, CategoriesError> {
+    let dataset: Dataset =
+        toml::from_str(source).map_err(|e| CategoriesError::Malformed(e.to_string()))?;
+    validate(&dataset.category)?;
+    Ok(dataset.category)
+}
+
+static CATEGORIES: OnceLock<Vec<Category>> = OnceLock::new();
+
+// Called once at startup; with no file, checks and installs the embedded dataset
+pub fn load_categories(path: Option<&Path>) -> Result<(), CategoriesError> {
+    let categories = match path {
+        Some(path) => {
+            let source =
+                fs::read_to_string(path).map_err(|e| CategoriesError::Unreadable(e.to_string()))?;
+            parse(&source)?
+        }
+        None => parse(DEFAULT_CATEGORIES)?,
+    };
+    info!("Loaded {} challenge categories", categories.len());
+    // Only the first load counts; the tests never call this
+    let _ = CATEGORIES.set(categories);
+    Ok(())
+}
+
+pub fn categories() -> &'static [Category] {
+    CATEGORIES.get_or_init(|| {
+        parse(DEFAULT_CATEGORIES).unwrap_or_else(|e| panic!("embedded categories: {}", e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn category(key: &str, emojis: &[&str]) -> String {
+        let emojis: Vec<String> = emojis
+            .iter()
+            .map(|emoji| format!("\"{}\"", emoji))
+            .collect();
+        format!(
+            "[[category]]\nkey = \"{}\"\nnames = {{ es = \"un {}\", en = \"a {}\", ca = \"un {}\" }}\nemojis = [{}]\n",
+            key,
+            key,
+            key,
+            key,
+            emojis.join(", ")
+        )
+    }
+
+    #[test]
+    fn test_embedded_categories_are_valid() {
+        let categories = parse(DEFAULT_CATEGORIES).unwrap();
+        assert!(categories.len() >= 8);
+        let weather = categories.iter().find(|c| c.key == "weather").unwrap();
+        assert_eq!(weather.name(Language::En), "a weather phenomenon");
+        assert!(!weather.emojis.contains(&"🔥".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_datasets_are_rejected() {
+        let fruit = category("fruit", &["🍎", "🍌", "🍇", "🍓"]);
+
+        assert_eq!(parse(&fruit), Err(CategoriesError::TooFewCategories));
+        assert_eq!(
+            parse(&(fruit.clone() + &category("tree", &["🌳", "🌲", "🌴"]))),
+            Err(CategoriesError::TooFewItems("tree".to_string()))
+        );
+        assert_eq!(
+            parse(&(fruit.clone() + &category("red", &["🍓", "🚒", "🌹", "🟥"]))),
+            Err(CategoriesError::RepeatedItem {
+                item: "🍓".to_string(),
+                first: "fruit".to_string(),
+                second: "red".to_string(),
+            })
+        );
+        assert_eq!(
+            parse(&(fruit.clone() + &category("weather", &["☀", "🌙", "☀️", "⛅"]))),
+            Err(CategoriesError::RepeatedItem {
+                item: "☀️".to_string(),
+                first: "weather".to_string(),
+                second: "weather".to_string(),
+            })
+        );
+
+        let tree = category("tree", &["🌳", "🌲", "🌴", "🌵"]);
+        assert_eq!(
+            parse(&(fruit.clone() + &tree.replace("a tree", "a tree?"))),
+            Err(CategoriesError::MalformedName {
+                key: "tree".to_string(),
+                language: Language::En,
+                name: "a tree?".to_string(),
+            })
+        );
+        assert_eq!(
+            parse(&(fruit.clone() + &tree.replace(", ca = \"un tree\"", ""))),
+            Err(CategoriesError::MissingName {
+                key: "tree".to_string(),
+                language: Language::Ca,
+            })
+        );
+        assert!(parse(&(fruit + &tree)).is_ok());
+    }
+}
@@ -0,0 +1,717 @@
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::captcha::{random_code, render_code, CAPTCHA_ALPHABET};
+use crate::categories::{categories, is_well_formed_name, item_key, Category, MIN_CATEGORY_ITEMS};
+use crate::i18n::{tr, Language};
+use crate::telegram::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+// --- Challenges new members have to solve ---
+//
+// Every kind implements `Challenge`; the chat configuration says which kinds are
+// enabled and with which weight, and `pick_challenge_kind` draws one of them.
+
+pub trait Challenge: Send + Sync {
+    fn kind(&self) -> ChallengeKind;
+
+    // Question shown above the keyboard, as Telegram HTML
+    fn question(&self) -> &str;
+
+    fn keyboard(&self) -> InlineKeyboardMarkup;
+
+    fn verify(&self, answer: &str) -> bool;
+
+    // What gets persisted so a pending challenge can still be verified after a restart
+    fn answer(&self) -> &str;
+
+    // PNG sent as a photo with the question as caption, for kinds that need one
+    fn image(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeKind {
+    #[default]
+    Emoji,
+    Math,
+    Image,
+}
+
+impl ChallengeKind {
+    pub const ALL: &'static [ChallengeKind] = &[
+        ChallengeKind::Emoji,
+        ChallengeKind::Math,
+        ChallengeKind::Image,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ChallengeKind::Emoji => "emoji",
+            ChallengeKind::Math => "math",
+            ChallengeKind::Image => "image",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+
+    // Custom categories only matter to the emoji challenge
+    pub fn generate(
+        self,
+        language: Language,
+        custom_categories: &[CustomCategory],
+    ) -> Box<dyn Challenge> {
+        match self {
+            ChallengeKind::Emoji => Box::new(EmojiChallenge::generate(language, custom_categories)),
+            ChallengeKind::Math => Box::new(MathChallenge::generate(language)),
+            ChallengeKind::Image => match ImageChallenge::generate(language) {
+                Ok(challenge) => Box::new(challenge),
+                Err(e) => {
+                    warn!("Failed to render image challenge, using emoji: {}", e);
+                    Box::new(EmojiChallenge::generate(language, custom_categories))
+                }
+            },
+        }
+    }
+
+    // Rebuilds a persisted challenge; only what verification needs survives
+    pub fn restore(self, answer: String) -> Box<dyn Challenge> {
+        let options = MultipleChoice {
+            question: String::new(),
+            options: Vec::new(),
+            correct_answer: answer,
+        };
+        match self {
+            ChallengeKind::Emoji => Box::new(EmojiChallenge { options }),
+            ChallengeKind::Math => Box::new(MathChallenge { options }),
+            ChallengeKind::Image => Box::new(ImageChallenge {
+                options,
+                image: Vec::new(),
+            }),
+        }
+    }
+}
+
+// A kind enabled in a chat and how often it is drawn relative to the others
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeightedChallengeKind {
+    pub kind: ChallengeKind,
+    pub weight: u32,
+}
+
+// Draws the kind of the next challenge; with nothing enabled, the emoji challenge
+pub fn pick_challenge_kind(enabled: &[WeightedChallengeKind]) -> ChallengeKind {
+    enabled
+        .choose_weighted(&mut rand::rng(), |enabled| enabled.weight)
+        .map(|enabled| enabled.kind)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChallengeKindsError {
+    InvalidWeight(String),
+    UnknownKind(String),
+    NoneEnabled,
+}
+
+impl ChallengeKindsError {
+    pub fn describe(&self, language: Language) -> String {
+        match self {
+            ChallengeKindsError::InvalidWeight(arg) => {
+                tr!(language, "commands.challenges_invalid_weight", arg = arg)
+            }
+            ChallengeKindsError::UnknownKind(name) => {
+                tr!(language, "commands.challenges_unknown_kind", name = name)
+            }
+            ChallengeKindsError::NoneEnabled => tr!(language, "commands.challenges_none_enabled"),
+        }
+    }
+}
+
+// Parses "emoji math:3" style lists; a missing weight counts as 1
+pub fn parse_challenge_kinds(
+    args: &[&str],
+) -> Result<Vec<WeightedChallengeKind>, ChallengeKindsError> {
+    let mut enabled: Vec<WeightedChallengeKind> = Vec::new();
+    for arg in args {
+        let (name, weight) = match arg.split_once(':') {
+            Some((name, weight)) => (
+                name,
+                weight
+                    .parse::<u32>()
+                    .map_err(|_| ChallengeKindsError::InvalidWeight(arg.to_string()))?,
+            ),
+            None => (*arg, 1),
+        };
+        let kind = ChallengeKind::from_name(name)
+            .ok_or_else(|| ChallengeKindsError::UnknownKind(name.to_string()))?;
+        if weight == 0 {
+            continue;
+        }
+        match enabled.iter_mut().find(|enabled| enabled.kind == kind) {
+            Some(enabled) => enabled.weight = weight,
+            None => enabled.push(WeightedChallengeKind { kind, weight }),
+        }
+    }
+    if enabled.is_empty() {
+        return Err(ChallengeKindsError::NoneEnabled);
+    }
+    Ok(enabled)
+}
+
+// One question, several buttons, exactly one of them right
+struct MultipleChoice {
+    question: String,
+    options: Vec<(String, String)>, // (button text, callback data)
+    correct_answer: String,
+}
+
+impl MultipleChoice {
+    // Gives every option a random callback id so the right one cannot be guessed
+    fn new(question: String, labels: Vec<String>, correct_label: &str) -> Self {
+        let mut correct_answer = String::new();
+        let options = labels
+            .into_iter()
+            .map(|label| {
+                let uuid = Uuid::new_v4().to_string();
+                if label == correct_label {
+                    correct_answer = uuid.clone();
+                }
+                (label, uuid)
+            })
+            .collect();
+        MultipleChoice {
+            question,
+            options,
+            correct_answer,
+        }
+    }
+
+    fn keyboard(&self) -> InlineKeyboardMarkup {
+        challenge_keyboard(&self.options)
+    }
+}
+
+pub fn challenge_keyboard(answer_options: &[(String, String)]) -> InlineKeyboardMarkup {
+    let row: Vec<InlineKeyboardButton> = answer_options
+        .iter()
+        .map(|(text, uuid)| InlineKeyboardButton {
+            text: text.clone(),
+            url: None,
+            callback_data: Some(uuid.clone()),
+        })
+        .collect();
+    let inline_keyboard = if row.is_empty() {
+        Vec::new()
+    } else {
+        vec![row]
+    };
+    InlineKeyboardMarkup { inline_keyboard }
+}
+
+// --- Categories added by a chat's admins ---
+
+pub const MAX_CATEGORY_ITEM_CHARS: usize = 16;
+
+// The name goes into the question as written ("una distro" -> "¿Cuál de estos NO
+// es una distro?"); items can be emojis or short words
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CustomCategory {
+    pub name: String,
+    pub items: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CategoryError {
+    EmptyName,
+    MalformedName(String),
+    NameTaken(String),
+    TooFewItems,
+    MalformedItem(String),
+    ItemTooLong(String),
+    RepeatedItem(String),
+    InBuiltInCategory {
+        item: String,
+        category: &'static Category,
+    },
+    InCustomCategory {
+        item: String,
+        name: String,
+    },
+}
+
+impl CategoryError {
+    pub fn describe(&self, language: Language) -> String {
+        match self {
+            CategoryError::EmptyName => tr!(language, "commands.category_empty_name"),
+            // The name is not echoed back, it may be the markup that broke it
+            CategoryError::MalformedName(_) => tr!(language, "commands.category_malformed_name"),
+            CategoryError::NameTaken(name) => {
+                tr!(language, "commands.category_name_taken", name = name)
+            }
+            CategoryError::TooFewItems => {
+                tr!(
+                    language,
+                    "commands.category_too_few",
+                    min = MIN_CATEGORY_ITEMS
+                )
+            }
+            // Same as the name: an item is listed in HTML replies
+            CategoryError::MalformedItem(_) => tr!(language, "commands.category_malformed_item"),
+            CategoryError::ItemTooLong(item) => tr!(
+                language,
+                "commands.category_item_too_long",
+                item = item,
+                max = MAX_CATEGORY_ITEM_CHARS
+            ),
+            CategoryError::RepeatedItem(item) => {
+                tr!(language, "commands.category_repeated", item = item)
+            }
+            CategoryError::InBuiltInCategory { item, category } => tr!(
+                language,
+                "commands.category_shared",
+                item = item,
+                category = category.name(language)
+            ),
+            CategoryError::InCustomCategory { item, name } => tr!(
+                language,
+                "commands.category_shared",
+                item = item,
+                category = name
+            ),
+        }
+    }
+}
+
+// Checks a new category against the built-in ones and the chat's others: no item
+// may belong to two categories, or the odd one out would be ambiguous
+pub fn new_custom_category(
+    name: &str,
+    items: &[&str],
+    existing: &[CustomCategory],
+) -> Result<CustomCategory, CategoryError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(CategoryError::EmptyName);
+    }
+    if !is_well_formed_name(name) {
+        return Err(CategoryError::MalformedName(name.to_string()));
+    }
+    if existing
+        .iter()
+        .any(|category| category.name.to_lowercase() == name.to_lowercase())
+    {
+        return Err(CategoryError::NameTaken(name.to_string()));
+    }
+    if items.len() < MIN_CATEGORY_ITEMS {
+        return Err(CategoryError::TooFewItems);
+    }
+
+    let mut seen = HashSet::new();
+    for item in items {
+        if item.contains(['<', '>', '&']) {
+            return Err(CategoryError::MalformedItem(item.to_string()));
+        }
+        if item.chars().count() > MAX_CATEGORY_ITEM_CHARS {
+            return Err(CategoryError::ItemTooLong(item.to_string()));
+        }
+        let key = item_key(item);
+        if !seen.insert(key.clone()) {
+            return Err(CategoryError::RepeatedItem(item.to_string()));
+        }
+        if let Some(category) = categories()
+            .iter()
+            .find(|category| category.emojis.iter().any(|emoji| item_key(emoji) == key))
+        {
+            return Err(CategoryError::InBuiltInCategory {
+                item: item.to_string(),
+                category,
+            });
+        }
+        if let Some(category) = existing.iter().find(|category| {
+            category
+                .items
+                .iter()
+                .any(|existing| item_key(existing) == key)
+        }) {
+            return Err(CategoryError::InCustomCategory {
+                item: item.to_string(),
+                name: category.name.clone(),
+            });
+        }
+    }
+
+    Ok(CustomCategory {
+        name: name.to_string(),
+        items: items.iter().map(|item| item.to_string()).collect(),
+    })
+}
+
+// --- Emoji categories: spot the emoji that does not belong ---
+//
+// The built-in categories come from the dataset in categories.rs
+
+pub struct EmojiChallenge {
+    options: MultipleChoice,
+}
+
+impl EmojiChallenge {
+    pub fn generate(language: Language, custom_categories: &[CustomCategory]) -> Self {
+        let mut rng = rand::rng();
+
+        // The built-in categories and the chat's own, as (name in the question, items)
+        let mut categories: Vec<(&str, Vec<&str>)> = categories()
+            .iter()
+            .map(|category| {
+                (
+                    category.name(language),
+                    category.emojis.iter().map(String::as_str).collect(),
+                )
+            })
+            .collect();
+        categories.extend(custom_categories.iter().map(|category| {
+            (
+                category.name.as_str(),
+                category.items.iter().map(String::as_str).collect(),
+            )
+        }));
+
+        // Select two different categories
+        let main_category_idx = rng.random_range(0..categories.len());
+        let mut different_category_idx = rng.random_range(0..categories.len());
+        while different_category_idx == main_category_idx {
+            different_category_idx = rng.random_range(0..categories.len());
+        }
+
+        let (main_name, main_items) = &categories[main_category_idx];
+        let (different_name, different_items) = &categories[different_category_idx];
+
+        // Select 4 emojis from main category
+        let mut all_emojis: Vec<&str> = main_items.choose_multiple(&mut rng, 4).copied().collect();
+
+        // Select 1 emoji from different category
+        let different_emoji = different_items[rng.random_range(0..different_items.len())];
+
+        // Create all 5 emojis and shuffle them
+        all_emojis.push(different_emoji);
+        all_emojis.shuffle(&mut rng);
+        debug!("Emoji challenge: {} among {}", different_name, main_name);
+
+        let question = tr!(language, "challenge.emoji_question", category = main_name);
+        let labels = all_emojis.iter().map(|emoji| emoji.to_string()).collect();
+        EmojiChallenge {
+            options: MultipleChoice::new(question, labels, different_emoji),
+        }
+    }
+}
+
+impl Challenge for EmojiChallenge {
+    fn kind(&self) -> ChallengeKind {
+        ChallengeKind::Emoji
+    }
+
+    fn question(&self) -> &str {
+        &self.options.question
+    }
+
+    fn keyboard(&self) -> InlineKeyboardMarkup {
+        self.options.keyboard()
+    }
+
+    fn verify(&self, answer: &str) -> bool {
+        answer == self.options.correct_answer
+    }
+
+    fn answer(&self) -> &str {
+        &self.options.correct_answer
+    }
+}
+
+// --- Arithmetic: pick the result of a small sum or subtraction ---
+
+pub struct MathChallenge {
+    options: MultipleChoice,
+}
+
+impl MathChallenge {
+    pub fn generate(language: Language) -> Self {
+        let mut rng = rand::rng();
+        let a = rng.random_range(2..=12);
+        let b = rng.random_range(2..=12);
+        let (expression, result) = if rng.random_bool(0.5) {
+            (format!("{} + {}", a, b), a + b)
+        } else {
+            let (a, b) = (a.max(b), a.min(b));
+            (format!("{} − {}", a, b), a - b)
+        };
+        let question = tr!(language, "challenge.math_question", expression = expression);
+
+        // Four wrong results close to the right one, so none stands out
+        let mut results = vec![result];
+        while results.len() < 5 {
+            let candidate = result + rng.random_range(-5..=5);
+            if candidate >= 0 && !results.contains(&candidate) {
+                results.push(candidate);
+            }
+        }
+        results.shuffle(&mut rng);
+
+        let labels = results.iter().map(|result| result.to_string()).collect();
+        MathChallenge {
+            options: MultipleChoice::new(question, labels, &result.to_string()),
+        }
+    }
+}
+
+impl Challenge for MathChallenge {
+    fn kind(&self) -> ChallengeKind {
+        ChallengeKind::Math
+    }
+
+    fn question(&self) -> &str {
+        &self.options.question
+    }
+
+    fn keyboard(&self) -> InlineKeyboardMarkup {
+        self.options.keyboard()
+    }
+
+    fn verify(&self, answer: &str) -> bool {
+        answer == self.options.correct_answer
+    }
+
+    fn answer(&self) -> &str {
+        &self.options.correct_answer
+    }
+}
+
+// --- Image: pick the code drawn in a distorted picture ---
+
+const IMAGE_CODE_LENGTH: usize = 5;
+
+pub struct ImageChallenge {
+    options: MultipleChoice,
+    image: Vec<u8>, // Empty once restored: the photo is already in the chat
+}
+
+impl ImageChallenge {
+    pub fn generate(language: Language) -> Result<Self, image::ImageError> {
+        let mut rng = rand::rng();
+        let code = random_code(IMAGE_CODE_LENGTH);
+        let image = render_code(&code)?;
+
+        // Decoys differ from the code in one or two characters, so the buttons
+        // alone give nothing away
+        let alphabet: Vec<char> = CAPTCHA_ALPHABET.chars().collect();
+        let mut codes = vec![code.clone()];
+        while codes.len() < 5 {
+            let mut decoy: Vec<char> = code.chars().collect();
+            for _ in 0..rng.random_range(1..=2) {
+                let position = rng.random_range(0..decoy.len());
+                decoy[position] = *alphabet.choose(&mut rng).unwrap();
+            }
+            let decoy: String = decoy.into_iter().collect();
+            if !codes.contains(&decoy) {
+                codes.push(decoy);
+            }
+        }
+        codes.shuffle(&mut rng);
+
+        let question = tr!(language, "challenge.image_question");
+        Ok(ImageChallenge {
+            options: MultipleChoice::new(question, codes, &code),
+            image,
+        })
+    }
+}
+
+impl Challenge for ImageChallenge {
+    fn kind(&self) -> ChallengeKind {
+        ChallengeKind::Image
+    }
+
+    fn question(&self) -> &str {
+        &self.options.question
+    }
+
+    fn keyboard(&self) -> InlineKeyboardMarkup {
+        self.options.keyboard()
+    }
+
+    fn verify(&self, answer: &str) -> bool {
+        answer == self.options.correct_answer
+    }
+
+    fn answer(&self) -> &str {
+        &self.options.correct_answer
+    }
+
+    fn image(&self) -> Option<&[u8]> {
+        (!self.image.is_empty()).then_some(self.image.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_category_challenge() {
+        println!("🧪 Probando el sistema de categorización...\n");
+
+        for i in 1..=5 {
+            let challenge = EmojiChallenge::generate(Language::Es, &[]);
+            let (question, correct_uuid, options) = (
+                challenge.question(),
+                challenge.answer(),
+                &challenge.options.options,
+            );
+
+            println!("--- Desafío {} ---", i);
+            println!("❓ {}", question);
+            println!("🔗 UUID correcto: {}", correct_uuid);
+            println!("📋 Opciones:");
+
+            // Verify that we have 5 options
+            assert_eq!(options.len(), 5);
+
+            // Verify that one of the options has the correct UUID
+            let has_correct_uuid = options.iter().any(|(_, uuid)| uuid == correct_uuid);
+            assert!(
+                has_correct_uuid,
+                "None of the options matches the correct UUID"
+            );
+
+            // Verify question format
+            assert!(
+                question.contains("¿Cuál de estos NO es "),
+                "Question doesn't have expected format"
+            );
+
+            for (j, (emoji, uuid)) in options.iter().enumerate() {
+                let marker = if uuid == correct_uuid { "✅" } else { "❌" };
+                println!("   {}. {} {} ({})", j + 1, emoji, marker, uuid);
+            }
+
+            println!();
+        }
+    }
+
+    #[test]
+    fn test_custom_categories_are_validated_and_drawn() {
+        let distros =
+            new_custom_category(" una distro ", &["Debian", "Arch", "Fedora", "Gentoo"], &[])
+                .unwrap();
+        assert_eq!(distros.name, "una distro");
+        let existing = [distros];
+
+        let add = |name, items: &[&str]| new_custom_category(name, items, &existing);
+        assert_eq!(
+            add("Una Distro", &["a", "b", "c", "d"]),
+            Err(CategoryError::NameTaken("Una Distro".to_string()))
+        );
+        assert_eq!(
+            add("un editor", &["vim", "emacs", "nano"]),
+            Err(CategoryError::TooFewItems)
+        );
+        assert_eq!(
+            add("un editor", &["vim", "emacs", "nano", "VIM"]),
+            Err(CategoryError::RepeatedItem("VIM".to_string()))
+        );
+        assert_eq!(
+            add("un editor", &["vim", "emacs", "nano", "visual studio code"]),
+            Err(CategoryError::ItemTooLong("visual studio code".to_string()))
+        );
+        assert!(matches!(
+            add("un clima", &["☀", "🌊", "🌫️", "🌬️"]),
+            Err(CategoryError::InBuiltInCategory { item, category })
+                if item == "☀" && category.key == "weather"
+        ));
+        assert_eq!(
+            add("un <b>editor</b>", &["vim", "emacs", "nano", "ed"]),
+            Err(CategoryError::MalformedName("un <b>editor</b>".to_string()))
+        );
+        assert_eq!(
+            add("un editor", &["vim", "emacs", "<b", "ed"]),
+            Err(CategoryError::MalformedItem("<b".to_string()))
+        );
+        assert_eq!(
+            add("un sistema", &["Linux", "arch", "BSD", "Hurd"]),
+            Err(CategoryError::InCustomCategory {
+                item: "arch".to_string(),
+                name: "una distro".to_string()
+            })
+        );
+
+        // With one custom category among eight, some draw soon involves it
+        let drawn = (0..200).any(|_| {
+            let challenge = EmojiChallenge::generate(Language::Es, &existing);
+            challenge.question().contains("una distro")
+                || challenge
+                    .options
+                    .options
+                    .iter()
+                    .any(|(label, _)| existing[0].items.contains(label))
+        });
+        assert!(drawn);
+    }
+
+    #[test]
+    fn test_math_challenge_verifies_the_result() {
+        let challenge = MathChallenge::generate(Language::Es);
+        let options = &challenge.options.options;
+        assert_eq!(options.len(), 5);
+        assert!(challenge.verify(challenge.answer()));
+        let wrong = options
+            .iter()
+            .find(|(_, uuid)| uuid != challenge.answer())
+            .unwrap();
+        assert!(!challenge.verify(&wrong.1));
+    }
+
+    #[test]
+    fn test_image_challenge_has_a_picture_and_distinct_codes() {
+        let challenge = ImageChallenge::generate(Language::Es).unwrap();
+        let options = &challenge.options.options;
+        assert_eq!(options.len(), 5);
+        assert!(challenge
+            .image()
+            .is_some_and(|png| png.starts_with(b"\x89PNG")));
+        assert!(challenge.verify(challenge.answer()));
+
+        let mut labels: Vec<&String> = options.iter().map(|(label, _)| label).collect();
+        labels.sort();
+        labels.dedup();
+        assert_eq!(labels.len(), 5);
+
+        let restored = ChallengeKind::Image.restore(challenge.answer().to_string());
+        assert!(restored.image().is_none());
+        assert!(restored.verify(challenge.answer()));
+    }
+
+    #[test]
+    fn test_parse_challenge_kinds() {
+        assert_eq!(
+            parse_challenge_kinds(&["emoji", "math:3"]),
+            Ok(vec![
+                WeightedChallengeKind {
+                    kind: ChallengeKind::Emoji,
+                    weight: 1
+                },
+                WeightedChallengeKind {
+                    kind: ChallengeKind::Math,
+                    weight: 3
+                },
+            ])
+        );
+        assert!(parse_challenge_kinds(&["emoji:0"]).is_err());
+        assert!(parse_challenge_kinds(&["sudoku"]).is_err());
+        assert_eq!(pick_challenge_kind(&[]), ChallengeKind::Emoji);
+    }
+}
@@ -0,0 +1,937 @@
+// End-to-end tests: the real poller, dispatcher and challenge flow run against
+// the in-process mock Bot API. The tokio clock is paused and held still, so time
+// only moves when a test advances it to drive timer_task.
+
+use serde_json::{json, Value};
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio::time::{advance, Duration, Instant};
+
+use crate::bot::{
+    challenge_duration, get_or_create_bot_config, resume_pending_challenges, unix_now,
+    update_bot_config, BotConfigState, ChallengeState,
+};
+use crate::dispatcher::AppState;
+use crate::mock_api::{settle, ClockHold, MockBotApi};
+use crate::penalty::{Penalty, StrikeMode};
+use crate::polling::run_polling;
+use crate::response_times::MIN_SAMPLES;
+use crate::storage::Storage;
+
+const CHAT_ID: i64 = -100123;
+const USER_ID: i64 = 42;
+
+struct TestBot {
+    _clock: ClockHold,
+    api: MockBotApi,
+    app: AppState,
+    polling: JoinHandle<()>,
+    data_dir: PathBuf,
+}
+
+impl TestBot {
+    async fn start() -> Self {
+        let clock = ClockHold::new();
+        let api = MockBotApi::start().await;
+        let data_dir = env::temp_dir().join(format!("expulsabot-e2e-{}", uuid::Uuid::new_v4()));
+        let app = app_state(&api, &data_dir);
+        let polling = tokio::spawn(run_polling(app.clone()));
+        TestBot {
+            _clock: clock,
+            api,
+            app,
+            polling,
+            data_dir,
+        }
+    }
+
+    // Stops the bot and starts a new one on the same data, as a redeploy would
+    async fn restart(&mut self) {
+        self.polling.abort();
+        // Dropping the challenges stops their timers, like the old process exiting
+        self.app.challenge_state.lock().await.clear();
+        settle().await;
+
+        self.app = app_state(&self.api, &self.data_dir);
+        resume_pending_challenges(
+            self.app.telegram_client.clone(),
+            &self.app.challenge_state,
+            &self.app.bot_config_state,
+            None,
+            None,
+        )
+        .await;
+        self.polling = tokio::spawn(run_polling(self.app.clone()));
+        // The resumed timers start counting once they first run
+        settle().await;
+    }
+
+    // Lets a user join and returns the challenge message once it is pending
+    async fn join(&self, user_id: i64) -> Value {
+        self.join_in(CHAT_ID, user_id).await
+    }
+
+    async fn join_in(&self, chat_id: i64, user_id: i64) -> Value {
+        let sent = self.api.calls("sendMessage").len();
+        self.api.push_update(json!({
+            "message": {
+                "message_id": 1,
+                "chat": { "id": chat_id, "type": "supergroup", "title": "Grupo" },
+                "from": user(user_id),
+                "date": 0,
+                "new_chat_members": [user(user_id)],
+            }
+        }));
+        let challenge = self.api.wait_for_calls("sendMessage", sent + 1).await;
+        // The challenge is registered right after Telegram confirms the message
+        while !self.has_pending_challenge_in(chat_id, user_id).await {
+            settle().await;
+        }
+        challenge
+    }
+
+    async fn correct_answer(&self, user_id: i64) -> String {
+        self.correct_answer_in(CHAT_ID, user_id).await
+    }
+
+    async fn correct_answer_in(&self, chat_id: i64, user_id: i64) -> String {
+        self.app.challenge_state.lock().await[&chat_id][&user_id]
+            .puzzle
+            .answer()
+            .to_string()
+    }
+
+    // Waits until a wrong answer has swapped the challenge and returns the new answer
+    async fn wait_for_new_challenge(&self, user_id: i64, old_answer: &str) -> String {
+        loop {
+            let answer = self.correct_answer(user_id).await;
+            if answer != old_answer {
+                return answer;
+            }
+            settle().await;
+        }
+    }
+
+    async fn has_pending_challenge(&self, user_id: i64) -> bool {
+        self.has_pending_challenge_in(CHAT_ID, user_id).await
+    }
+
+    async fn has_pending_challenge_in(&self, chat_id: i64, user_id: i64) -> bool {
+        self.app
+            .challenge_state
+            .lock()
+            .await
+            .get(&chat_id)
+            .is_some_and(|users| users.contains_key(&user_id))
+    }
+
+    // Clicks a button of the challenge posted for `challenged_user_id`
+    async fn click(&self, user_id: i64, challenged_user_id: i64, challenge: &Value, data: &str) {
+        self.click_in(CHAT_ID, user_id, challenged_user_id, challenge, data)
+            .await
+    }
+
+    async fn click_in(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        challenged_user_id: i64,
+        challenge: &Value,
+        data: &str,
+    ) {
+        let message_id = self.app.challenge_state.lock().await[&chat_id][&challenged_user_id]
+            .challenge_message_id;
+        self.api.push_update(json!({
+            "callback_query": {
+                "id": format!("cb-{}", user_id),
+                "from": user(user_id),
+                "message": {
+                    "message_id": message_id,
+                    "chat": { "id": chat_id, "type": "supergroup", "title": "Grupo" },
+                    "from": { "id": 1, "is_bot": true, "first_name": "ExpulsaBot" },
+                    "text": challenge["text"],
+                },
+                "data": data,
+            }
+        }));
+    }
+}
+
+impl Drop for TestBot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+fn app_state(api: &MockBotApi, data_dir: &PathBuf) -> AppState {
+    let storage = Storage::new(data_dir);
+    AppState {
+        telegram_client: Arc::new(api.telegram()),
+        challenge_state: ChallengeState::new(storage.clone()),
+        bot_config_state: BotConfigState::load(storage),
+        open_client: None,
+        matrix_client: None,
+        start_time: Instant::now(),
+    }
+}
+
+fn user(id: i64) -> Value {
+    json!({ "id": id, "is_bot": false, "first_name": format!("Usuario{}", id) })
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_join_restricts_and_sends_challenge() {
+    let bot = TestBot::start().await;
+    let challenge = bot.join(USER_ID).await;
+
+    let restrict = bot.api.wait_for_call("restrictChatMember").await;
+    assert_eq!(restrict["user_id"], USER_ID);
+    assert_eq!(restrict["permissions"]["can_send_messages"], false);
+
+    assert_eq!(challenge["chat_id"], CHAT_ID);
+    let buttons: Vec<&Value> = challenge["reply_markup"]["inline_keyboard"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|row| row.as_array().unwrap())
+        .collect();
+    assert_eq!(buttons.len(), 5);
+    assert!(bot.has_pending_challenge(USER_ID).await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_user_with_markup_in_their_name_is_challenged() {
+    let bot = TestBot::start().await;
+    let member = json!({ "id": USER_ID, "is_bot": false, "first_name": "<b>" });
+    bot.api.push_update(json!({
+        "message": {
+            "message_id": 1,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+            "from": member,
+            "date": 0,
+            "new_chat_members": [member],
+        }
+    }));
+
+    // The name is escaped, so Telegram accepts the challenge and the user stays restricted
+    let challenge = bot.api.wait_for_call("sendMessage").await;
+    assert!(challenge["text"].as_str().unwrap().contains("&lt;b&gt;"));
+    while !bot.has_pending_challenge(USER_ID).await {
+        settle().await;
+    }
+    assert_eq!(bot.api.calls("restrictChatMember").len(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_correct_answer_restores_chat_permissions() {
+    let bot = TestBot::start().await;
+    let challenge = bot.join(USER_ID).await;
+    let answer = bot.correct_answer(USER_ID).await;
+
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, &answer).await;
+
+    let toast = bot.api.wait_for_call("answerCallbackQuery").await;
+    assert_eq!(toast["text"], "✅ ¡Respuesta correcta!");
+    let restore = bot.api.wait_for_calls("restrictChatMember", 2).await;
+    assert_eq!(restore["permissions"]["can_send_messages"], true);
+    assert_eq!(restore["permissions"]["can_send_photos"], true);
+    assert_eq!(restore["permissions"]["can_pin_messages"], false);
+    assert!(!bot.has_pending_challenge(USER_ID).await);
+
+    // The timer was cancelled, so nobody gets banned once the deadline passes
+    advance(challenge_duration() + Duration::from_secs(60)).await;
+    settle().await;
+    assert!(bot.api.calls("banChatMember").is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_unanswered_challenge_bans_after_timeout() {
+    let bot = TestBot::start().await;
+    bot.join(USER_ID).await;
+
+    advance(challenge_duration() - Duration::from_secs(1)).await;
+    settle().await;
+    assert!(bot.api.calls("banChatMember").is_empty());
+
+    advance(Duration::from_secs(1)).await;
+    let ban = bot.api.wait_for_call("banChatMember").await;
+    assert_eq!(ban["chat_id"], CHAT_ID);
+    assert_eq!(ban["user_id"], USER_ID);
+    while bot.has_pending_challenge(USER_ID).await {
+        settle().await;
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_countdown_and_outcome_edit_the_challenge_message() {
+    let bot = TestBot::start().await;
+    let challenge = bot.join(USER_ID).await;
+    let message_id = challenge_message_id(&bot).await;
+    assert!(challenge["text"]
+        .as_str()
+        .unwrap()
+        .contains("⏱️ Tiempo restante: "));
+
+    // The time left is rewritten with the buttons still there
+    advance(Duration::from_secs(31)).await;
+    let countdown = bot.api.wait_for_call("editMessageText").await;
+    assert_eq!(countdown["message_id"], message_id);
+    assert!(countdown["text"]
+        .as_str()
+        .unwrap()
+        .contains("⏱️ Tiempo restante: "));
+    assert_eq!(
+        countdown["reply_markup"]["inline_keyboard"],
+        challenge["reply_markup"]["inline_keyboard"]
+    );
+
+    // The outcome replaces the challenge instead of coming as a new message
+    let answer = bot.correct_answer(USER_ID).await;
+    bot.click(USER_ID, USER_ID, &challenge, &answer).await;
+    let outcome = bot.api.wait_for_calls("editMessageText", 2).await;
+    assert_eq!(outcome["message_id"], message_id);
+    assert_eq!(
+        outcome["text"],
+        format!(
+            "<b>Usuario{}</b> ha pasado la verificación. ¡Bienvenido!",
+            USER_ID
+        )
+    );
+    assert_eq!(outcome["reply_markup"]["inline_keyboard"], json!([]));
+    assert_eq!(bot.api.calls("sendMessage").len(), 1);
+
+    // No more countdowns once the challenge is over
+    advance(Duration::from_secs(60)).await;
+    settle().await;
+    assert_eq!(bot.api.calls("editMessageText").len(), 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_welcome_command_customizes_the_passed_message() {
+    let bot = TestBot::start().await;
+    bot.api.set_admins(&[1]);
+    let commands = [
+        "/welcome set Hola {mention}, lee las normas: {rules_link}",
+        "/welcome rules https://example.org/rules",
+        "/welcome button Normas https://example.org/rules",
+        "/welcome keep on",
+    ];
+    for (i, command) in commands.iter().enumerate() {
+        bot.api.push_update(json!({
+            "message": {
+                "message_id": 1,
+                "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+                "from": user(1),
+                "text": command,
+            }
+        }));
+        bot.api.wait_for_calls("sendMessage", i + 1).await;
+    }
+    let replies = bot.api.calls("sendMessage");
+    assert_eq!(replies[0]["text"], "👋 Mensaje de bienvenida actualizado");
+
+    let challenge = bot.join(USER_ID).await;
+    let message_id = challenge_message_id(&bot).await;
+    let answer = bot.correct_answer(USER_ID).await;
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, &answer).await;
+
+    let welcome = bot.api.wait_for_call("editMessageText").await;
+    assert_eq!(welcome["message_id"], message_id);
+    assert_eq!(
+        welcome["text"],
+        format!(
+            "Hola <a href=\"tg://user?id={}\">Usuario{}</a>, lee las normas: https://example.org/rules",
+            USER_ID, USER_ID
+        )
+    );
+    assert_eq!(
+        welcome["reply_markup"]["inline_keyboard"],
+        json!([[{ "text": "Normas", "url": "https://example.org/rules" }]])
+    );
+
+    // Kept: nothing is cleaned up once the delay is over
+    advance(Duration::from_secs(60)).await;
+    settle().await;
+    assert!(bot.api.calls("deleteMessage").is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_wrong_answer_bans() {
+    let bot = TestBot::start().await;
+    let challenge = bot.join(USER_ID).await;
+
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, "wrong").await;
+
+    let toast = bot.api.wait_for_call("answerCallbackQuery").await;
+    assert_eq!(toast["text"], "❌ Respuesta incorrecta");
+    let ban = bot.api.wait_for_call("banChatMember").await;
+    assert_eq!(ban["user_id"], USER_ID);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_failure_is_not_announced_when_the_penalty_fails() {
+    let bot = TestBot::start().await;
+    let challenge = bot.join(USER_ID).await;
+    bot.api
+        .fail_next("banChatMember", 400, "Bad Request: not enough rights");
+
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, "wrong").await;
+    bot.api.wait_for_call("banChatMember").await;
+    settle().await;
+
+    // The user is still in the chat, so the chat is not told otherwise
+    let announced = bot
+        .api
+        .calls("editMessageText")
+        .into_iter()
+        .chain(bot.api.calls("sendMessage"))
+        .any(|call| call["text"].as_str().unwrap().contains("Has fallado"));
+    assert!(!announced);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_too_fast_answer_bans() {
+    let bot = TestBot::start().await;
+    let challenge = bot.join(USER_ID).await;
+    let answer = bot.correct_answer(USER_ID).await;
+
+    bot.click(USER_ID, USER_ID, &challenge, &answer).await;
+
+    let toast = bot.api.wait_for_call("answerCallbackQuery").await;
+    assert_eq!(toast["text"], "⚡ Respuesta demasiado rápida");
+    bot.api.wait_for_call("banChatMember").await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_too_fast_threshold_adapts_to_the_chat() {
+    let bot = TestBot::start().await;
+    bot.api.set_admins(&[1]);
+    // Humans in this chat take 10 seconds, so 3 is implausibly fast
+    for _ in 0..MIN_SAMPLES {
+        bot.app
+            .challenge_state
+            .record_response_time(CHAT_ID, Duration::from_secs(10))
+            .await;
+    }
+    bot.api.push_update(json!({
+        "message": {
+            "message_id": 1,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+            "from": user(1),
+            "text": "/stats",
+        }
+    }));
+    let stats = bot.api.wait_for_call("sendMessage").await;
+    assert!(stats["text"]
+        .as_str()
+        .unwrap()
+        .ends_with("⏱️ Tiempo (mediana): 10.0 s\n🚨 Demasiado rápido: menos de 5.0 s"));
+
+    let challenge = bot.join(USER_ID).await;
+    let answer = bot.correct_answer(USER_ID).await;
+    advance(Duration::from_secs(3)).await;
+    bot.click(USER_ID, USER_ID, &challenge, &answer).await;
+
+    let toast = bot.api.wait_for_call("answerCallbackQuery").await;
+    assert_eq!(toast["text"], "⚡ Respuesta demasiado rápida");
+    bot.api.wait_for_call("banChatMember").await;
+    // Flagged answers do not count as a human's
+    let response_times = bot.app.challenge_state.response_times(CHAT_ID).await;
+    assert_eq!(response_times.len(), MIN_SAMPLES);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limited_outcome_does_not_hold_up_other_answers() {
+    const OTHER_CHAT_ID: i64 = -100456;
+    let bot = TestBot::start().await;
+    let first = bot.join(USER_ID).await;
+    let second = bot.join_in(OTHER_CHAT_ID, USER_ID).await;
+    let answer = bot.correct_answer_in(OTHER_CHAT_ID, USER_ID).await;
+    advance(Duration::from_secs(5)).await;
+
+    // The outcome in one chat waits out a flood error on its edit...
+    bot.api.rate_limit_next("editMessageText", 60);
+    bot.click(USER_ID, USER_ID, &first, "wrong").await;
+    let ban = bot.api.wait_for_call("banChatMember").await;
+    assert_eq!(ban["user_id"], USER_ID);
+    bot.api.wait_for_call("editMessageText").await;
+    settle().await;
+
+    // ...while an answer in another chat goes through
+    bot.click_in(OTHER_CHAT_ID, USER_ID, USER_ID, &second, &answer)
+        .await;
+    let toast = bot.api.wait_for_calls("answerCallbackQuery", 2).await;
+    assert_eq!(toast["text"], "✅ ¡Respuesta correcta!");
+    let welcome = bot.api.wait_for_calls("editMessageText", 2).await;
+    assert_eq!(welcome["chat_id"], OTHER_CHAT_ID);
+
+    advance(Duration::from_secs(60)).await;
+    let outcome = bot.api.wait_for_calls("editMessageText", 3).await;
+    assert_eq!(outcome["chat_id"], CHAT_ID);
+    assert!(outcome["text"].as_str().unwrap().contains("Has fallado"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_challenge_pending_at_restart_can_still_be_passed() {
+    let mut bot = TestBot::start().await;
+    let challenge = bot.join(USER_ID).await;
+    bot.restart().await;
+    assert!(bot.has_pending_challenge(USER_ID).await);
+
+    let answer = bot.correct_answer(USER_ID).await;
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, &answer).await;
+
+    let toast = bot.api.wait_for_call("answerCallbackQuery").await;
+    assert_eq!(toast["text"], "✅ ¡Respuesta correcta!");
+    let restore = bot.api.wait_for_calls("restrictChatMember", 2).await;
+    assert_eq!(restore["permissions"]["can_send_messages"], true);
+    assert!(!bot.has_pending_challenge(USER_ID).await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_challenge_pending_at_restart_still_times_out() {
+    let mut bot = TestBot::start().await;
+    bot.join(USER_ID).await;
+    bot.restart().await;
+
+    advance(challenge_duration() + Duration::from_secs(1)).await;
+    let ban = bot.api.wait_for_call("banChatMember").await;
+    assert_eq!(ban["chat_id"], CHAT_ID);
+    assert_eq!(ban["user_id"], USER_ID);
+    assert!(!bot.has_pending_challenge(USER_ID).await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_request_to_an_upgraded_group_moves_it_to_the_supergroup() {
+    const SUPERGROUP_ID: i64 = -100789;
+    let bot = TestBot::start().await;
+    update_bot_config(&bot.app.bot_config_state, CHAT_ID, |config| {
+        config.max_attempts = 2;
+    })
+    .await;
+    bot.join(USER_ID).await;
+
+    // The countdown edit is the first request to learn of the upgrade
+    bot.api.migrate_next("editMessageText", SUPERGROUP_ID);
+    advance(Duration::from_secs(31)).await;
+    let retried = bot.api.wait_for_calls("editMessageText", 2).await;
+    assert_eq!(bot.api.calls("editMessageText")[0]["chat_id"], CHAT_ID);
+    assert_eq!(retried["chat_id"], SUPERGROUP_ID);
+
+    while !bot.has_pending_challenge_in(SUPERGROUP_ID, USER_ID).await {
+        settle().await;
+    }
+    assert!(!bot.has_pending_challenge(USER_ID).await);
+    let config = get_or_create_bot_config(&bot.app.bot_config_state, SUPERGROUP_ID).await;
+    assert_eq!(config.max_attempts, 2);
+
+    // The challenge still times out, now in the supergroup
+    advance(challenge_duration()).await;
+    let ban = bot.api.wait_for_call("banChatMember").await;
+    assert_eq!(ban["chat_id"], SUPERGROUP_ID);
+    assert_eq!(ban["user_id"], USER_ID);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_click_from_another_user_is_rejected() {
+    let bot = TestBot::start().await;
+    let challenge = bot.join(USER_ID).await;
+    let answer = bot.correct_answer(USER_ID).await;
+
+    advance(Duration::from_secs(5)).await;
+    bot.click(7, USER_ID, &challenge, &answer).await;
+
+    let alert = bot.api.wait_for_call("answerCallbackQuery").await;
+    assert_eq!(alert["show_alert"], true);
+    assert!(bot.has_pending_challenge(USER_ID).await);
+    assert_eq!(bot.api.calls("restrictChatMember").len(), 1);
+    assert!(bot.api.calls("banChatMember").is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_failed_restriction_sends_no_challenge() {
+    let bot = TestBot::start().await;
+    bot.api
+        .fail_next("restrictChatMember", 400, "Bad Request: not enough rights");
+    bot.api.push_update(json!({
+        "message": {
+            "message_id": 1,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+            "from": user(USER_ID),
+            "new_chat_members": [user(USER_ID)],
+        }
+    }));
+
+    bot.api.wait_for_call("restrictChatMember").await;
+    settle().await;
+    assert!(bot.api.calls("sendMessage").is_empty());
+    assert!(!bot.has_pending_challenge(USER_ID).await);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_permissions_override_is_restored_after_a_pass() {
+    let bot = TestBot::start().await;
+    bot.api.set_admins(&[1]);
+    bot.api.push_update(json!({
+        "message": {
+            "message_id": 1,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+            "from": user(1),
+            "text": "/permissions messages,polls",
+        }
+    }));
+    let reply = bot.api.wait_for_call("sendMessage").await;
+    assert_eq!(
+        reply["text"],
+        "🔐 Permisos tras superar el desafío: messages, polls"
+    );
+
+    let challenge = bot.join(USER_ID).await;
+    let answer = bot.correct_answer(USER_ID).await;
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, &answer).await;
+
+    let restore = bot.api.wait_for_calls("restrictChatMember", 2).await;
+    assert_eq!(restore["permissions"]["can_send_polls"], true);
+    assert_eq!(restore["permissions"]["can_send_photos"], false);
+    assert!(bot.api.calls("getChat").is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_image_challenge_is_sent_as_a_photo() {
+    let bot = TestBot::start().await;
+    bot.api.set_admins(&[1]);
+    bot.api.push_update(json!({
+        "message": {
+            "message_id": 1,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+            "from": user(1),
+            "text": "/challenges image",
+        }
+    }));
+    let reply = bot.api.wait_for_call("sendMessage").await;
+    assert_eq!(reply["text"], "🧩 Desafíos activos: image:1");
+
+    bot.api.push_update(json!({
+        "message": {
+            "message_id": 2,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+            "from": user(USER_ID),
+            "date": 0,
+            "new_chat_members": [user(USER_ID)],
+        }
+    }));
+    let photo = bot.api.wait_for_call("sendPhoto").await;
+    assert_eq!(photo["chat_id"], CHAT_ID);
+    assert_eq!(photo["photo"]["file_name"], "challenge.png");
+    assert_eq!(photo["photo"]["content_type"], "image/png");
+    assert!(photo["caption"]
+        .as_str()
+        .unwrap()
+        .contains("¿Qué código aparece en la imagen?"));
+    assert_eq!(
+        photo["reply_markup"]["inline_keyboard"][0]
+            .as_array()
+            .unwrap()
+            .len(),
+        5
+    );
+
+    while !bot.has_pending_challenge(USER_ID).await {
+        settle().await;
+    }
+    let answer = bot.correct_answer(USER_ID).await;
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &photo, &answer).await;
+
+    let toast = bot.api.wait_for_call("answerCallbackQuery").await;
+    assert_eq!(toast["text"], "✅ ¡Respuesta correcta!");
+    assert_eq!(bot.api.calls("sendMessage").len(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_wrong_answer_with_attempts_left_gets_a_fresh_challenge() {
+    let bot = TestBot::start().await;
+    bot.api.set_admins(&[1]);
+    bot.api.push_update(json!({
+        "message": {
+            "message_id": 1,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+            "from": user(1),
+            "text": "/attempts 2",
+        }
+    }));
+    let reply = bot.api.wait_for_call("sendMessage").await;
+    assert_eq!(reply["text"], "🎯 Intentos por desafío: 2");
+
+    let challenge = bot.join(USER_ID).await;
+    let first_answer = bot.correct_answer(USER_ID).await;
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, "wrong").await;
+
+    let toast = bot.api.wait_for_call("answerCallbackQuery").await;
+    assert_eq!(toast["text"], "❌ Respuesta incorrecta. Te queda 1 intento");
+    let edit = bot.api.wait_for_call("editMessageText").await;
+    assert_eq!(edit["chat_id"], CHAT_ID);
+    assert_eq!(edit["message_id"], challenge_message_id(&bot).await);
+    assert!(edit["text"].as_str().unwrap().contains("Nuevo desafío"));
+    assert!(bot.api.calls("banChatMember").is_empty());
+
+    // The buttons of the first challenge are gone, the new ones work
+    let answer = bot.wait_for_new_challenge(USER_ID, &first_answer).await;
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &edit, &answer).await;
+    let toast = bot.api.wait_for_calls("answerCallbackQuery", 2).await;
+    assert_eq!(toast["text"], "✅ ¡Respuesta correcta!");
+    assert!(bot.api.calls("banChatMember").is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_last_wrong_attempt_bans() {
+    let bot = TestBot::start().await;
+    update_bot_config(&bot.app.bot_config_state, CHAT_ID, |config| {
+        config.max_attempts = 2;
+    })
+    .await;
+    let challenge = bot.join(USER_ID).await;
+    let first_answer = bot.correct_answer(USER_ID).await;
+
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, "wrong").await;
+    bot.wait_for_new_challenge(USER_ID, &first_answer).await;
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, "wrong").await;
+
+    let toast = bot.api.wait_for_calls("answerCallbackQuery", 2).await;
+    assert_eq!(toast["text"], "❌ Respuesta incorrecta");
+    let ban = bot.api.wait_for_call("banChatMember").await;
+    assert_eq!(ban["user_id"], USER_ID);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_penalty_command_sets_what_a_failure_costs() {
+    let bot = TestBot::start().await;
+    bot.api.set_admins(&[1]);
+    for (i, command) in ["/penalty kick", "/penalty revoke off"].iter().enumerate() {
+        bot.api.push_update(json!({
+            "message": {
+                "message_id": 1,
+                "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+                "from": user(1),
+                "text": command,
+            }
+        }));
+        bot.api.wait_for_calls("sendMessage", i + 1).await;
+    }
+    let replies = bot.api.calls("sendMessage");
+    assert_eq!(
+        replies[0]["text"],
+        "⚖️ Sanción a quien falla el desafío: expulsión (puede volver a entrar)"
+    );
+
+    let challenge = bot.join(USER_ID).await;
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, "wrong").await;
+
+    // A kick is a ban lifted right away, and the history stays
+    let ban = bot.api.wait_for_call("banChatMember").await;
+    assert_eq!(ban["user_id"], USER_ID);
+    assert_eq!(ban["revoke_messages"], false);
+    assert!(ban.get("until_date").is_none());
+    let unban = bot.api.wait_for_call("unbanChatMember").await;
+    assert_eq!(unban["user_id"], USER_ID);
+    assert_eq!(unban["only_if_banned"], true);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_repeat_failures_escalate_the_penalty() {
+    let bot = TestBot::start().await;
+    update_bot_config(&bot.app.bot_config_state, CHAT_ID, |config| {
+        config.penalty = Penalty::Mute;
+    })
+    .await;
+
+    // Failures from before strikes were turned on do not count
+    let challenge = bot.join(USER_ID).await;
+    advance(Duration::from_secs(5)).await;
+    bot.click(USER_ID, USER_ID, &challenge, "wrong").await;
+    while bot.has_pending_challenge(USER_ID).await {
+        settle().await;
+    }
+    update_bot_config(&bot.app.bot_config_state, CHAT_ID, |config| {
+        config.strikes = StrikeMode::Chat;
+    })
+    .await;
+
+    for strike in 1..=3 {
+        let challenge = bot.join(USER_ID).await;
+        advance(Duration::from_secs(5)).await;
+        bot.click(USER_ID, USER_ID, &challenge, "wrong").await;
+        bot.api.wait_for_calls("banChatMember", strike).await;
+        while bot.has_pending_challenge(USER_ID).await {
+            settle().await;
+        }
+    }
+
+    // A kick, then a day's ban, then a permanent one
+    let bans = bot.api.calls("banChatMember");
+    assert_eq!(bans.len(), 3);
+    assert!(bans[0].get("until_date").is_none());
+    assert_eq!(bot.api.calls("unbanChatMember").len(), 1);
+    let day = bans[1]["until_date"].as_u64().unwrap() - unix_now();
+    assert!((24 * 3600 - 60..=24 * 3600).contains(&day));
+    assert!(bans[2].get("until_date").is_none());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_challenge_speaks_the_users_language() {
+    let bot = TestBot::start().await;
+    bot.api.set_admins(&[1]);
+    bot.api.push_update(json!({
+        "message": {
+            "message_id": 1,
+            "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+            "from": user(1),
+            "text": "/language ca",
+        }
+    }));
+    let reply = bot.api.wait_for_call("sendMessage").await;
+    assert_eq!(reply["text"], "🌐 Idioma del grup: Català");
+
+    // An English client gets English, a language without a catalog the group's
+    for (user_id, language_code, welcome) in
+        [(USER_ID, "en-GB", "Welcome"), (43, "de", "Benvingut")]
+    {
+        let mut member = user(user_id);
+        member["language_code"] = json!(language_code);
+        let sent = bot.api.calls("sendMessage").len();
+        bot.api.push_update(json!({
+            "message": {
+                "message_id": 2,
+                "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+                "from": member,
+                "date": 0,
+                "new_chat_members": [member],
+            }
+        }));
+        let challenge = bot.api.wait_for_calls("sendMessage", sent + 1).await;
+        assert!(challenge["text"].as_str().unwrap().starts_with(welcome));
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_category_command_adds_validated_categories() {
+    let bot = TestBot::start().await;
+    bot.api.set_admins(&[1]);
+    let replies = [
+        (
+            "/category add una distro: Debian Arch Fedora Gentoo",
+            "🗂️ Categoría añadida: una distro (Debian Arch Fedora Gentoo)",
+        ),
+        (
+            "/category add un sistema: Linux BSD Hurd ARCH",
+            "⚠️ No se pudo añadir la categoría: ARCH ya está en la categoría una distro",
+        ),
+        (
+            "/category add un clima: 🌊 🌫️ 🌬️ ❄",
+            "⚠️ No se pudo añadir la categoría: ❄ ya está en la categoría un fenómeno climático",
+        ),
+    ];
+    for (i, (command, expected)) in replies.iter().enumerate() {
+        bot.api.push_update(json!({
+            "message": {
+                "message_id": i + 1,
+                "chat": { "id": CHAT_ID, "type": "supergroup", "title": "Grupo" },
+                "from": user(1),
+                "text": command,
+            }
+        }));
+        let reply = bot.api.wait_for_calls("sendMessage", i + 1).await;
+        assert_eq!(reply["text"], *expected);
+    }
+
+    let config = get_or_create_bot_config(&bot.app.bot_config_state, CHAT_ID).await;
+    assert_eq!(config.custom_categories.len(), 1);
+    assert_eq!(config.custom_categories[0].items.len(), 4);
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_private_mode_challenges_through_a_deep_link() {
+    let bot = TestBot::start().await;
+    update_bot_config(&bot.app.bot_config_state, CHAT_ID, |config| {
+        config.private_challenges = true;
+    })
+    .await;
+
+    // The group only gets a link to the bot's private chat
+    let prompt = bot.join(USER_ID).await;
+    assert_eq!(prompt["chat_id"], CHAT_ID);
+    let url = prompt["reply_markup"]["inline_keyboard"][0][0]["url"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let token = url
+        .strip_prefix("https://t.me/expulsa_test_bot?start=")
+        .unwrap();
+    assert!(token.starts_with("verify_"));
+
+    let start = |user_id: i64| {
+        json!({
+            "message": {
+                "message_id": 1,
+                "chat": { "id": user_id, "type": "private" },
+                "from": user(user_id),
+                "text": format!("/start {}", token),
+            }
+        })
+    };
+
+    // Somebody else's link is refused
+    bot.api.push_update(start(43));
+    let refused = bot.api.wait_for_calls("sendMessage", 2).await;
+    assert_eq!(refused["chat_id"], 43);
+    assert_eq!(
+        refused["text"],
+        "⌛ Este enlace ya no es válido o no es para ti"
+    );
+
+    bot.api.push_update(start(USER_ID));
+    let challenge = bot.api.wait_for_calls("sendMessage", 3).await;
+    assert_eq!(challenge["chat_id"], USER_ID);
+    assert!(challenge["text"].as_str().unwrap().contains("Grupo"));
+    while challenge_message_id(&bot).await != 1003 {
+        settle().await;
+    }
+
+    let answer = bot.correct_answer(USER_ID).await;
+    advance(Duration::from_secs(5)).await;
+    bot.api.push_update(json!({
+        "callback_query": {
+            "id": "cb-private",
+            "from": user(USER_ID),
+            "message": {
+                "message_id": 1003,
+                "chat": { "id": USER_ID, "type": "private" },
+                "from": { "id": 1, "is_bot": true, "first_name": "ExpulsaBot" },
+                "text": challenge["text"],
+            },
+            "data": answer,
+        }
+    }));
+    let toast = bot.api.wait_for_call("answerCallbackQuery").await;
+    assert_eq!(toast["text"], "✅ ¡Respuesta correcta!");
+    let restore = bot.api.wait_for_calls("restrictChatMember", 2).await;
+    assert_eq!(restore["chat_id"], CHAT_ID);
+    assert!(!bot.has_pending_challenge(USER_ID).await);
+}
+
+async fn challenge_message_id(bot: &TestBot) -> u64 {
+    bot.app.challenge_state.lock().await[&CHAT_ID][&USER_ID].challenge_message_id
+}
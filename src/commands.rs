@@ -1,19 +1,52 @@
 use std::env;
 use std::sync::Arc;
-use tokio::time::Instant;
+use tokio::time::{Duration, Instant};
 use tracing::error;
 
-use crate::bot::{get_or_create_bot_config, BotConfig, BotConfigState};
-use crate::telegram::Telegram;
+use crate::bot::{
+    chat_language, get_or_create_bot_config, member_permissions, min_response_time, open_deep_link,
+    update_bot_config, BotConfigState, ChallengeState, DEEP_LINK_PREFIX,
+};
+use crate::challenge::{
+    new_custom_category, parse_challenge_kinds, ChallengeKind, WeightedChallengeKind,
+};
+use crate::i18n::{tr, Language};
+use crate::penalty::{Penalty, StrikeMode, MAX_TEMPBAN_MINUTES};
+use crate::response_times::MIN_SAMPLES;
+use crate::telegram::{Chat, ChatPermissions, Telegram, User};
+use crate::welcome::{
+    escape_html, new_button, placeholder_list, validate_template, validate_url, WelcomeError,
+};
 
+#[allow(clippy::too_many_arguments)]
 pub async fn handle_command(
     text: &str,
-    chat_id: i64,
-    user_id: i64,
+    chat: &Chat,
+    message_thread_id: Option<i64>,
+    from: &User,
     telegram_client: &Arc<Telegram>,
     bot_config_state: &BotConfigState,
+    challenge_state: &ChallengeState,
     start_time: &Instant,
 ) -> Result<(), String> {
+    let chat_id = chat.id;
+    let user_id = from.id;
+
+    // Anyone may start the bot in private, which is how deep-link challenges are opened
+    if chat.chat_type == "private" && text.starts_with("/start") {
+        return handle_private_start_command(
+            text,
+            chat_id,
+            from,
+            telegram_client,
+            bot_config_state,
+            challenge_state,
+        )
+        .await;
+    }
+
+    let language = chat_language(bot_config_state, chat_id).await;
+
     // For all commands, check if user is admin using Telegram API
     match telegram_client.is_chat_admin(chat_id, user_id).await {
         Ok(is_admin) => {
@@ -21,7 +54,8 @@ pub async fn handle_command(
                 if telegram_client
                     .send_message(
                         chat_id,
-                        "❌ Solo los administradores del grupo pueden usar comandos del bot",
+                        message_thread_id,
+                        &tr!(language, "commands.admin_only"),
                     )
                     .await
                     .is_err()
@@ -34,7 +68,11 @@ pub async fn handle_command(
         Err(e) => {
             error!("Failed to check admin status for user {}: {}", user_id, e);
             if telegram_client
-                .send_message(chat_id, "❌ Error al verificar permisos de administrador")
+                .send_message(
+                    chat_id,
+                    message_thread_id,
+                    &tr!(language, "commands.admin_check_failed"),
+                )
                 .await
                 .is_err()
             {
@@ -46,30 +84,215 @@ pub async fn handle_command(
 
     // Process admin-only commands
     if text.starts_with("/start") {
-        handle_start_command(chat_id, telegram_client).await
+        handle_start_command(chat_id, message_thread_id, telegram_client, language).await
     } else if text.starts_with("/help") {
-        handle_help_command(chat_id, telegram_client).await
+        handle_help_command(chat_id, message_thread_id, telegram_client, language).await
     } else if text.starts_with("/status") {
-        handle_status_command(chat_id, telegram_client, start_time).await
+        handle_status_command(
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            start_time,
+            language,
+        )
+        .await
     } else if text.starts_with("/whitelist") {
-        handle_whitelist_command(text, chat_id, telegram_client, bot_config_state).await
+        handle_whitelist_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
     } else if text.starts_with("/unwhitelist") {
-        handle_unwhitelist_command(text, chat_id, telegram_client, bot_config_state).await
+        handle_unwhitelist_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
     } else if text.starts_with("/stats") {
-        handle_stats_command(chat_id, telegram_client, bot_config_state).await
+        handle_stats_command(
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            challenge_state,
+            language,
+        )
+        .await
     } else if text.starts_with("/notify") {
-        handle_notify_command(text, chat_id, telegram_client, bot_config_state).await
+        handle_notify_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/permissions") {
+        handle_permissions_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/challenges") {
+        handle_challenges_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/category") {
+        handle_category_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/private") {
+        handle_private_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/penalty") {
+        handle_penalty_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/strikes") {
+        handle_strikes_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/welcome") {
+        handle_welcome_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/attempts") {
+        handle_attempts_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/topic") {
+        handle_topic_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
+    } else if text.starts_with("/language") {
+        handle_language_command(
+            text,
+            chat_id,
+            message_thread_id,
+            telegram_client,
+            bot_config_state,
+            language,
+        )
+        .await
     } else {
         Ok(()) // Unknown command, do nothing
     }
 }
 
-async fn handle_start_command(chat_id: i64, telegram_client: &Arc<Telegram>) -> Result<(), String> {
+async fn handle_start_command(
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    language: Language,
+) -> Result<(), String> {
     if telegram_client
-        .send_message(
-            chat_id,
-            "¡Hola! Soy tu bot de Telegram. Úsame para administrar el acceso al grupo.",
-        )
+        .send_message(chat_id, message_thread_id, &tr!(language, "commands.start"))
+        .await
+        .is_err()
+    {
+        error!("Failed to send start message to chat {}", chat_id);
+        return Err("Failed to send start message".to_string());
+    }
+    Ok(())
+}
+
+// /start verify_<token> sends the challenge a group announced with a link
+async fn handle_private_start_command(
+    text: &str,
+    chat_id: i64,
+    from: &User,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    challenge_state: &ChallengeState,
+) -> Result<(), String> {
+    let token = text
+        .split_whitespace()
+        .nth(1)
+        .and_then(|payload| payload.strip_prefix(DEEP_LINK_PREFIX));
+    let language = Language::for_user(from.language_code.as_deref(), Language::default());
+    let reply = match token {
+        Some(token) => {
+            if open_deep_link(
+                telegram_client,
+                challenge_state,
+                bot_config_state,
+                chat_id,
+                from.id,
+                token,
+            )
+            .await
+            {
+                return Ok(());
+            }
+            tr!(language, "challenge.private_invalid")
+        }
+        None => tr!(language, "commands.start"),
+    };
+
+    if telegram_client
+        .send_message(chat_id, None, &reply)
         .await
         .is_err()
     {
@@ -79,39 +302,26 @@ async fn handle_start_command(chat_id: i64, telegram_client: &Arc<Telegram>) ->
     Ok(())
 }
 
-async fn handle_help_command(chat_id: i64, telegram_client: &Arc<Telegram>) -> Result<(), String> {
+async fn handle_help_command(
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    language: Language,
+) -> Result<(), String> {
     let ban_bots_directly = env::var("BAN_BOTS_DIRECTLY")
         .unwrap_or_else(|_| "true".to_string())
         .to_lowercase()
         == "true";
 
     let bot_treatment = if ban_bots_directly {
-        "Los bots son expulsados automáticamente"
+        tr!(language, "commands.help_bots_banned")
     } else {
-        "Los bots reciben el mismo challenge que los usuarios"
+        tr!(language, "commands.help_bots_challenged")
     };
-
-    let help_text = format!(
-        "🤖 <b>ExpulsaBot - Protección Anti-Bot</b>\n\n\
-        📋 <b>Comandos disponibles:</b>\n\
-        • /start - Iniciar el bot\n\
-        • /help - Ver esta ayuda\n\
-        • /status - Ver estado del bot\n\
-        • /whitelist &lt;bot_id&gt; - Permitir bot específico\n\
-        • /unwhitelist &lt;bot_id&gt; - Remover bot de lista blanca\n\
-        • /stats - Ver estadísticas del grupo\n\
-        • /notify &lt;on|off&gt; - Activar/desactivar notificaciones\n\n\
-        🔧 <b>Configuración actual:</b>\n\
-        {}\n\n\
-        ⚠️ <b>Importante:</b>\n\
-        Solo los administradores de Telegram pueden usar comandos del bot.\n\n\
-        👤 <b>Para usuarios humanos:</b>\n\
-        Los nuevos miembros serán desafiados para verificar que no son bots.",
-        bot_treatment
-    );
+    let help_text = tr!(language, "commands.help", bot_treatment = bot_treatment);
 
     if telegram_client
-        .send_message(chat_id, &help_text)
+        .send_message(chat_id, message_thread_id, &help_text)
         .await
         .is_err()
     {
@@ -123,42 +333,42 @@ async fn handle_help_command(chat_id: i64, telegram_client: &Arc<Telegram>) -> R
 
 async fn handle_status_command(
     chat_id: i64,
+    message_thread_id: Option<i64>,
     telegram_client: &Arc<Telegram>,
     start_time: &Instant,
+    language: Language,
 ) -> Result<(), String> {
     let uptime = start_time.elapsed();
     let total_seconds = uptime.as_secs();
 
-    let status_text = if total_seconds < 60 {
-        format!(
-            "🟢 <b>Bot Estado:</b> Activo\n⏱️ <b>Tiempo en línea:</b> {} segundos",
-            total_seconds
-        )
+    let uptime = if total_seconds < 60 {
+        tr!(language, "commands.uptime_seconds", seconds = total_seconds)
     } else if total_seconds < 3600 {
-        let minutes = total_seconds / 60;
-        let seconds = total_seconds % 60;
-        format!(
-            "🟢 <b>Bot Estado:</b> Activo\n⏱️ <b>Tiempo en línea:</b> {} minutos y {} segundos",
-            minutes, seconds
+        tr!(
+            language,
+            "commands.uptime_minutes",
+            minutes = total_seconds / 60,
+            seconds = total_seconds % 60
         )
     } else if total_seconds < 86400 {
-        let hours = total_seconds / 3600;
-        let minutes = (total_seconds % 3600) / 60;
-        format!(
-            "🟢 <b>Bot Estado:</b> Activo\n⏱️ <b>Tiempo en línea:</b> {} horas y {} minutos",
-            hours, minutes
+        tr!(
+            language,
+            "commands.uptime_hours",
+            hours = total_seconds / 3600,
+            minutes = (total_seconds % 3600) / 60
         )
     } else {
-        let days = total_seconds / 86400;
-        let hours = (total_seconds % 86400) / 3600;
-        format!(
-            "🟢 <b>Bot Estado:</b> Activo\n⏱️ <b>Tiempo en línea:</b> {} días y {} horas",
-            days, hours
+        tr!(
+            language,
+            "commands.uptime_days",
+            days = total_seconds / 86400,
+            hours = (total_seconds % 86400) / 3600
         )
     };
+    let status_text = tr!(language, "commands.status", uptime = uptime);
 
     if telegram_client
-        .send_message(chat_id, &status_text)
+        .send_message(chat_id, message_thread_id, &status_text)
         .await
         .is_err()
     {
@@ -171,24 +381,29 @@ async fn handle_status_command(
 async fn handle_whitelist_command(
     text: &str,
     chat_id: i64,
+    message_thread_id: Option<i64>,
     telegram_client: &Arc<Telegram>,
     bot_config_state: &BotConfigState,
+    language: Language,
 ) -> Result<(), String> {
     let parts: Vec<&str> = text.split_whitespace().collect();
     if parts.len() >= 2 {
         if let Ok(bot_id) = parts[1].parse::<i64>() {
-            let mut state = bot_config_state.lock().await;
-            let config = state.entry(chat_id).or_insert_with(|| BotConfig {
-                whitelisted_bots: Vec::new(),
-                notify_on_ban: true,
-                banned_bots_count: 0,
-            });
-            if !config.whitelisted_bots.contains(&bot_id) {
-                config.whitelisted_bots.push(bot_id);
+            let added = update_bot_config(bot_config_state, chat_id, |config| {
+                if config.whitelisted_bots.contains(&bot_id) {
+                    false
+                } else {
+                    config.whitelisted_bots.push(bot_id);
+                    true
+                }
+            })
+            .await;
+            if added {
                 if telegram_client
                     .send_message(
                         chat_id,
-                        &format!("✅ Bot {} agregado a la lista blanca", bot_id),
+                        message_thread_id,
+                        &tr!(language, "commands.whitelist_added", id = bot_id),
                     )
                     .await
                     .is_err()
@@ -199,7 +414,8 @@ async fn handle_whitelist_command(
             } else if telegram_client
                 .send_message(
                     chat_id,
-                    &format!("⚠️ Bot {} ya está en la lista blanca", bot_id),
+                    message_thread_id,
+                    &tr!(language, "commands.whitelist_exists", id = bot_id),
                 )
                 .await
                 .is_err()
@@ -209,7 +425,11 @@ async fn handle_whitelist_command(
             }
         }
     } else if telegram_client
-        .send_message(chat_id, "Uso: /whitelist <bot_id>")
+        .send_message(
+            chat_id,
+            message_thread_id,
+            &tr!(language, "commands.whitelist_usage"),
+        )
         .await
         .is_err()
     {
@@ -222,42 +442,55 @@ async fn handle_whitelist_command(
 async fn handle_unwhitelist_command(
     text: &str,
     chat_id: i64,
+    message_thread_id: Option<i64>,
     telegram_client: &Arc<Telegram>,
     bot_config_state: &BotConfigState,
+    language: Language,
 ) -> Result<(), String> {
     let parts: Vec<&str> = text.split_whitespace().collect();
     if parts.len() >= 2 {
         if let Ok(bot_id) = parts[1].parse::<i64>() {
-            let mut state = bot_config_state.lock().await;
-            if let Some(config) = state.get_mut(&chat_id) {
+            let removed = update_bot_config(bot_config_state, chat_id, |config| {
                 if let Some(pos) = config.whitelisted_bots.iter().position(|&x| x == bot_id) {
                     config.whitelisted_bots.remove(pos);
-                    if telegram_client
-                        .send_message(
-                            chat_id,
-                            &format!("❌ Bot {} removido de la lista blanca", bot_id),
-                        )
-                        .await
-                        .is_err()
-                    {
-                        error!("Failed to send unwhitelist confirmation");
-                        return Err("Failed to send unwhitelist confirmation".to_string());
-                    }
-                } else if telegram_client
+                    true
+                } else {
+                    false
+                }
+            })
+            .await;
+            if removed {
+                if telegram_client
                     .send_message(
                         chat_id,
-                        &format!("⚠️ Bot {} no está en la lista blanca", bot_id),
+                        message_thread_id,
+                        &tr!(language, "commands.unwhitelist_removed", id = bot_id),
                     )
                     .await
                     .is_err()
                 {
-                    error!("Failed to send unwhitelist warning");
-                    return Err("Failed to send unwhitelist warning".to_string());
+                    error!("Failed to send unwhitelist confirmation");
+                    return Err("Failed to send unwhitelist confirmation".to_string());
                 }
+            } else if telegram_client
+                .send_message(
+                    chat_id,
+                    message_thread_id,
+                    &tr!(language, "commands.unwhitelist_missing", id = bot_id),
+                )
+                .await
+                .is_err()
+            {
+                error!("Failed to send unwhitelist warning");
+                return Err("Failed to send unwhitelist warning".to_string());
             }
         }
     } else if telegram_client
-        .send_message(chat_id, "Uso: /unwhitelist <bot_id>")
+        .send_message(
+            chat_id,
+            message_thread_id,
+            &tr!(language, "commands.unwhitelist_usage"),
+        )
         .await
         .is_err()
     {
@@ -267,20 +500,53 @@ async fn handle_unwhitelist_command(
     Ok(())
 }
 
+// Seconds with one decimal, for the response time stats
+fn format_seconds(duration: Duration) -> String {
+    format!("{:.1}", duration.as_secs_f64())
+}
+
 async fn handle_stats_command(
     chat_id: i64,
+    message_thread_id: Option<i64>,
     telegram_client: &Arc<Telegram>,
     bot_config_state: &BotConfigState,
+    challenge_state: &ChallengeState,
+    language: Language,
 ) -> Result<(), String> {
     let config = get_or_create_bot_config(bot_config_state, chat_id).await;
-    let stats_msg = format!(
-        "📊 <b>Estadísticas Anti-Bot</b>\n🤖 Bots expulsados: {}\n📝 Bots en lista blanca: {}\n🔔 Notificaciones: {}",
-        config.banned_bots_count,
-        config.whitelisted_bots.len(),
-        if config.notify_on_ban { "Activadas" } else { "Desactivadas" }
+    let notifications = if config.notify_on_ban {
+        tr!(language, "commands.stats_notifications_on")
+    } else {
+        tr!(language, "commands.stats_notifications_off")
+    };
+    let response_times = challenge_state.response_times(chat_id).await;
+    let threshold = format_seconds(response_times.threshold(min_response_time()));
+    let speed = match response_times.percentile(0.5) {
+        Some(median) if response_times.len() >= MIN_SAMPLES => tr!(
+            language,
+            "commands.stats_speed",
+            samples = response_times.len(),
+            median = format_seconds(median),
+            threshold = threshold
+        ),
+        _ => tr!(
+            language,
+            "commands.stats_speed_learning",
+            samples = response_times.len(),
+            needed = MIN_SAMPLES,
+            threshold = threshold
+        ),
+    };
+    let stats_msg = tr!(
+        language,
+        "commands.stats",
+        banned = config.banned_bots_count,
+        whitelisted = config.whitelisted_bots.len(),
+        notifications = notifications,
+        speed = speed
     );
     if telegram_client
-        .send_message(chat_id, &stats_msg)
+        .send_message(chat_id, message_thread_id, &stats_msg)
         .await
         .is_err()
     {
@@ -293,22 +559,25 @@ async fn handle_stats_command(
 async fn handle_notify_command(
     text: &str,
     chat_id: i64,
+    message_thread_id: Option<i64>,
     telegram_client: &Arc<Telegram>,
     bot_config_state: &BotConfigState,
+    language: Language,
 ) -> Result<(), String> {
     let parts: Vec<&str> = text.split_whitespace().collect();
     if parts.len() >= 2 {
         let enable = parts[1] == "on" || parts[1] == "true" || parts[1] == "1";
-        let mut state = bot_config_state.lock().await;
-        let config = state.entry(chat_id).or_insert_with(|| BotConfig {
-            whitelisted_bots: Vec::new(),
-            notify_on_ban: true,
-            banned_bots_count: 0,
-        });
-        config.notify_on_ban = enable;
-        let status = if enable { "activadas" } else { "desactivadas" };
+        update_bot_config(bot_config_state, chat_id, |config| {
+            config.notify_on_ban = enable;
+        })
+        .await;
+        let reply = if enable {
+            tr!(language, "commands.notify_on")
+        } else {
+            tr!(language, "commands.notify_off")
+        };
         if telegram_client
-            .send_message(chat_id, &format!("🔔 Notificaciones {}", status))
+            .send_message(chat_id, message_thread_id, &reply)
             .await
             .is_err()
         {
@@ -316,7 +585,11 @@ async fn handle_notify_command(
             return Err("Failed to send notify confirmation".to_string());
         }
     } else if telegram_client
-        .send_message(chat_id, "Uso: /notify <on|off>")
+        .send_message(
+            chat_id,
+            message_thread_id,
+            &tr!(language, "commands.notify_usage"),
+        )
         .await
         .is_err()
     {
@@ -325,3 +598,687 @@ async fn handle_notify_command(
     }
     Ok(())
 }
+
+// In forum groups, picks the topic where challenges and ban notifications are posted
+async fn handle_topic_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let topic = match parts.get(1) {
+        Some(&"off") | Some(&"general") => Some(None),
+        Some(arg) => arg.parse::<i64>().ok().map(Some),
+        // Without arguments, the topic the command was sent from
+        None => message_thread_id.map(Some),
+    };
+
+    let reply = match topic {
+        Some(topic) => {
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.challenge_topic_id = topic;
+            })
+            .await;
+            match topic {
+                Some(topic_id) => tr!(language, "commands.topic_set", topic = topic_id),
+                None => tr!(language, "commands.topic_general"),
+            }
+        }
+        None => tr!(language, "commands.topic_usage"),
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send topic confirmation");
+        return Err("Failed to send topic confirmation".to_string());
+    }
+    Ok(())
+}
+
+// Overrides the permissions restored after a pass; by default the chat's own are used
+async fn handle_permissions_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let names: Vec<&str> = text
+        .split_whitespace()
+        .skip(1)
+        .flat_map(|arg| arg.split(','))
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    let reply = match names.as_slice() {
+        [] => {
+            let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+            let permissions = member_permissions(telegram_client, bot_config_state, chat_id).await;
+            let source = if config.member_permissions.is_some() {
+                tr!(language, "commands.permissions_custom")
+            } else {
+                tr!(language, "commands.permissions_chat")
+            };
+            tr!(
+                language,
+                "commands.permissions_show",
+                source = source,
+                granted = permissions.granted_names().join(", "),
+                available = ChatPermissions::field_names().join(", ")
+            )
+        }
+        ["off"] | ["default"] => {
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.member_permissions = None;
+            })
+            .await;
+            tr!(language, "commands.permissions_reset")
+        }
+        _ => match ChatPermissions::from_names(names.iter().copied()) {
+            Ok(permissions) => {
+                let granted = permissions.granted_names().join(", ");
+                update_bot_config(bot_config_state, chat_id, |config| {
+                    config.member_permissions = Some(permissions);
+                })
+                .await;
+                tr!(language, "commands.permissions_set", granted = granted)
+            }
+            Err(unknown) => tr!(
+                language,
+                "commands.permissions_unknown",
+                name = escape_html(unknown),
+                available = ChatPermissions::field_names().join(", ")
+            ),
+        },
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send permissions confirmation");
+        return Err("Failed to send permissions confirmation".to_string());
+    }
+    Ok(())
+}
+
+// Chooses which kinds of challenge the chat uses and how often each one is drawn
+async fn handle_challenges_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let args: Vec<&str> = text.split_whitespace().skip(1).collect();
+    let available = ChallengeKind::ALL
+        .iter()
+        .map(|kind| kind.name())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let reply = if args.is_empty() {
+        let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+        let enabled = if config.challenge_kinds.is_empty() {
+            ChallengeKind::default().name().to_string()
+        } else {
+            format_challenge_kinds(&config.challenge_kinds)
+        };
+        tr!(
+            language,
+            "commands.challenges_show",
+            enabled = enabled,
+            available = available
+        )
+    } else {
+        match parse_challenge_kinds(&args) {
+            Ok(kinds) => {
+                let enabled = format_challenge_kinds(&kinds);
+                update_bot_config(bot_config_state, chat_id, |config| {
+                    config.challenge_kinds = kinds;
+                })
+                .await;
+                tr!(language, "commands.challenges_set", enabled = enabled)
+            }
+            Err(e) => tr!(
+                language,
+                "commands.challenges_error",
+                error = e.describe(language),
+                available = available
+            ),
+        }
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send challenges confirmation");
+        return Err("Failed to send challenges confirmation".to_string());
+    }
+    Ok(())
+}
+
+// /category lists the chat's own categories, /category add <name>: <item> ... adds
+// one and /category remove <name> drops it
+async fn handle_category_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let args = text
+        .split_once(char::is_whitespace)
+        .map_or("", |(_, args)| args);
+    let (action, rest) = args
+        .trim()
+        .split_once(char::is_whitespace)
+        .unwrap_or((args.trim(), ""));
+
+    let reply = match action {
+        "" => {
+            let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+            let categories = if config.custom_categories.is_empty() {
+                tr!(language, "commands.category_none")
+            } else {
+                config
+                    .custom_categories
+                    .iter()
+                    .map(|category| {
+                        // Escaped all the same, in case one was saved before items were checked
+                        escape_html(&format!(
+                            "• {}: {}",
+                            category.name,
+                            category.items.join(" ")
+                        ))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            };
+            tr!(language, "commands.category_list", categories = categories)
+        }
+        "add" => match rest.split_once(':') {
+            Some((name, items)) => {
+                let items: Vec<&str> = items.split_whitespace().collect();
+                let added = update_bot_config(bot_config_state, chat_id, |config| {
+                    let added = new_custom_category(name, &items, &config.custom_categories);
+                    if let Ok(category) = &added {
+                        config.custom_categories.push(category.clone());
+                    }
+                    added
+                })
+                .await;
+                match added {
+                    Ok(category) => tr!(
+                        language,
+                        "commands.category_added",
+                        name = escape_html(&category.name),
+                        items = escape_html(&category.items.join(" "))
+                    ),
+                    Err(e) => tr!(
+                        language,
+                        "commands.category_error",
+                        error = e.describe(language)
+                    ),
+                }
+            }
+            None => tr!(language, "commands.category_usage"),
+        },
+        "remove" if !rest.trim().is_empty() => {
+            let name = rest.trim();
+            let removed = update_bot_config(bot_config_state, chat_id, |config| {
+                let before = config.custom_categories.len();
+                config
+                    .custom_categories
+                    .retain(|category| category.name.to_lowercase() != name.to_lowercase());
+                config.custom_categories.len() != before
+            })
+            .await;
+            let name = escape_html(name);
+            if removed {
+                tr!(language, "commands.category_removed", name = name)
+            } else {
+                tr!(language, "commands.category_missing", name = name)
+            }
+        }
+        _ => tr!(language, "commands.category_usage"),
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send category confirmation");
+        return Err("Failed to send category confirmation".to_string());
+    }
+    Ok(())
+}
+
+async fn handle_private_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let reply = match parts.get(1).copied() {
+        None => {
+            let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+            let state = if config.private_challenges {
+                tr!(language, "commands.private_state_on")
+            } else {
+                tr!(language, "commands.private_state_off")
+            };
+            tr!(language, "commands.private_show", state = state)
+        }
+        Some(arg @ ("on" | "off")) => {
+            let enable = arg == "on";
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.private_challenges = enable;
+            })
+            .await;
+            if enable {
+                tr!(language, "commands.private_on")
+            } else {
+                tr!(language, "commands.private_off")
+            }
+        }
+        Some(_) => tr!(language, "commands.private_usage"),
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send private mode confirmation");
+        return Err("Failed to send private mode confirmation".to_string());
+    }
+    Ok(())
+}
+
+async fn handle_penalty_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let reply = match &parts[1..] {
+        [] => {
+            let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+            let revoke = if config.revoke_messages {
+                tr!(language, "commands.penalty_revoke_yes")
+            } else {
+                tr!(language, "commands.penalty_revoke_no")
+            };
+            tr!(
+                language,
+                "commands.penalty_show",
+                penalty = config.penalty.describe(language),
+                revoke = revoke,
+                max = MAX_TEMPBAN_MINUTES
+            )
+        }
+        ["revoke", arg @ ("on" | "off")] => {
+            let revoke = *arg == "on";
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.revoke_messages = revoke;
+            })
+            .await;
+            if revoke {
+                tr!(language, "commands.penalty_revoke_on")
+            } else {
+                tr!(language, "commands.penalty_revoke_off")
+            }
+        }
+        args => match Penalty::parse(args) {
+            Some(penalty) => {
+                update_bot_config(bot_config_state, chat_id, |config| {
+                    config.penalty = penalty;
+                })
+                .await;
+                tr!(
+                    language,
+                    "commands.penalty_set",
+                    penalty = penalty.describe(language)
+                )
+            }
+            None => tr!(
+                language,
+                "commands.penalty_usage",
+                max = MAX_TEMPBAN_MINUTES
+            ),
+        },
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send penalty confirmation");
+        return Err("Failed to send penalty confirmation".to_string());
+    }
+    Ok(())
+}
+
+fn strike_mode_text(mode: StrikeMode, language: Language) -> String {
+    match mode {
+        StrikeMode::Off => tr!(language, "commands.strikes_off"),
+        StrikeMode::Chat => tr!(language, "commands.strikes_chat"),
+        StrikeMode::Global => tr!(language, "commands.strikes_global"),
+    }
+}
+
+async fn handle_strikes_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let reply = match parts.get(1).map(|arg| StrikeMode::from_name(arg)) {
+        None => {
+            let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+            tr!(
+                language,
+                "commands.strikes_show",
+                state = strike_mode_text(config.strikes, language)
+            )
+        }
+        Some(Some(mode)) => {
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.strikes = mode;
+            })
+            .await;
+            strike_mode_text(mode, language)
+        }
+        Some(None) => tr!(language, "commands.strikes_usage"),
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send strikes confirmation");
+        return Err("Failed to send strikes confirmation".to_string());
+    }
+    Ok(())
+}
+
+async fn handle_welcome_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let args = text
+        .split_once(char::is_whitespace)
+        .map_or("", |(_, args)| args);
+    let (action, rest) = args
+        .trim()
+        .split_once(char::is_whitespace)
+        .unwrap_or((args.trim(), ""));
+    let rest = rest.trim();
+    let error_reply = |e: WelcomeError| {
+        tr!(
+            language,
+            "commands.welcome_error",
+            error = e.describe(language)
+        )
+    };
+
+    let reply = match (action, rest) {
+        ("", _) => {
+            let welcome = get_or_create_bot_config(bot_config_state, chat_id)
+                .await
+                .welcome;
+            let none = tr!(language, "commands.welcome_none");
+            let buttons = if welcome.buttons.is_empty() {
+                none.clone()
+            } else {
+                welcome
+                    .buttons
+                    .iter()
+                    .map(|button| {
+                        format!(
+                            "\n• {}: {}",
+                            escape_html(&button.text),
+                            escape_html(&button.url)
+                        )
+                    })
+                    .collect()
+            };
+            tr!(
+                language,
+                "commands.welcome_show",
+                template = welcome
+                    .template
+                    .as_deref()
+                    .map_or_else(|| tr!(language, "commands.welcome_default"), escape_html),
+                rules = welcome.rules_link.as_deref().map_or(none, escape_html),
+                buttons = buttons,
+                keep = if welcome.keep {
+                    tr!(language, "commands.welcome_kept")
+                } else {
+                    tr!(language, "commands.welcome_deleted")
+                },
+                placeholders = placeholder_list()
+            )
+        }
+        ("set", template) => match validate_template(template) {
+            Ok(()) => {
+                update_bot_config(bot_config_state, chat_id, |config| {
+                    config.welcome.template = Some(template.to_string());
+                })
+                .await;
+                tr!(language, "commands.welcome_set")
+            }
+            Err(e) => error_reply(e),
+        },
+        ("reset", "") => {
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.welcome.template = None;
+            })
+            .await;
+            tr!(language, "commands.welcome_reset")
+        }
+        ("button", "clear") => {
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.welcome.buttons.clear();
+            })
+            .await;
+            tr!(language, "commands.welcome_buttons_cleared")
+        }
+        ("button", button) if button.contains(char::is_whitespace) => {
+            // The URL is the last word, the text everything before it
+            let (button_text, url) = button.rsplit_once(char::is_whitespace).unwrap_or_default();
+            let added = update_bot_config(bot_config_state, chat_id, |config| {
+                let added = new_button(button_text, url, &config.welcome.buttons);
+                if let Ok(button) = &added {
+                    config.welcome.buttons.push(button.clone());
+                }
+                added
+            })
+            .await;
+            match added {
+                Ok(button) => tr!(
+                    language,
+                    "commands.welcome_button_added",
+                    text = escape_html(&button.text)
+                ),
+                Err(e) => error_reply(e),
+            }
+        }
+        ("rules", "clear") => {
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.welcome.rules_link = None;
+            })
+            .await;
+            tr!(language, "commands.welcome_rules_cleared")
+        }
+        ("rules", url) if !url.is_empty() => match validate_url(url) {
+            Ok(()) => {
+                update_bot_config(bot_config_state, chat_id, |config| {
+                    config.welcome.rules_link = Some(url.to_string());
+                })
+                .await;
+                tr!(
+                    language,
+                    "commands.welcome_rules_set",
+                    url = escape_html(url)
+                )
+            }
+            Err(e) => error_reply(e),
+        },
+        ("keep", arg @ ("on" | "off")) => {
+            let keep = arg == "on";
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.welcome.keep = keep;
+            })
+            .await;
+            if keep {
+                tr!(language, "commands.welcome_keep_on")
+            } else {
+                tr!(language, "commands.welcome_keep_off")
+            }
+        }
+        _ => tr!(
+            language,
+            "commands.welcome_usage",
+            placeholders = placeholder_list()
+        ),
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send welcome confirmation");
+        return Err("Failed to send welcome confirmation".to_string());
+    }
+    Ok(())
+}
+
+// Highest number of attempts a chat can allow
+const MAX_ATTEMPTS: u32 = 10;
+
+// How many answers a new member may try; every wrong one brings a fresh challenge
+async fn handle_attempts_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let reply = match parts.get(1).map(|arg| arg.parse::<u32>()) {
+        None => {
+            let config = get_or_create_bot_config(bot_config_state, chat_id).await;
+            tr!(
+                language,
+                "commands.attempts_show",
+                attempts = config.max_attempts,
+                max = MAX_ATTEMPTS
+            )
+        }
+        Some(Ok(attempts)) if (1..=MAX_ATTEMPTS).contains(&attempts) => {
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.max_attempts = attempts;
+            })
+            .await;
+            tr!(language, "commands.attempts_set", attempts = attempts)
+        }
+        Some(_) => tr!(language, "commands.attempts_usage", max = MAX_ATTEMPTS),
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send attempts confirmation");
+        return Err("Failed to send attempts confirmation".to_string());
+    }
+    Ok(())
+}
+
+async fn handle_language_command(
+    text: &str,
+    chat_id: i64,
+    message_thread_id: Option<i64>,
+    telegram_client: &Arc<Telegram>,
+    bot_config_state: &BotConfigState,
+    language: Language,
+) -> Result<(), String> {
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let available = Language::ALL
+        .iter()
+        .map(|language| format!("{} ({})", language.code(), language.native_name()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let reply = match parts.get(1).map(|arg| Language::from_code(arg)) {
+        None => tr!(
+            language,
+            "commands.language_show",
+            language = language.native_name(),
+            available = available
+        ),
+        // Confirm in the language just chosen
+        Some(Some(chosen)) => {
+            update_bot_config(bot_config_state, chat_id, |config| {
+                config.language = chosen;
+            })
+            .await;
+            tr!(
+                chosen,
+                "commands.language_set",
+                language = chosen.native_name()
+            )
+        }
+        Some(None) => tr!(language, "commands.language_usage", available = available),
+    };
+
+    if telegram_client
+        .send_message(chat_id, message_thread_id, &reply)
+        .await
+        .is_err()
+    {
+        error!("Failed to send language confirmation");
+        return Err("Failed to send language confirmation".to_string());
+    }
+    Ok(())
+}
+
+fn format_challenge_kinds(kinds: &[WeightedChallengeKind]) -> String {
+    kinds
+        .iter()
+        .map(|enabled| format!("{}:{}", enabled.kind.name(), enabled.weight))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,849 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+use crate::bot::{
+    announce_outcome, announce_welcome, attempts_left_text, challenge_topic, chat_language,
+    failure_sanction, follow_chat_migrations, get_or_create_bot_config, migrate_chat,
+    process_join_request, process_my_chat_member, process_new_member, resolve_join_request,
+    restore_member_permissions, retry_challenge, update_bot_config, BotConfigState, ChallengeState,
+};
+use crate::commands::handle_command;
+use crate::i18n::{tr, Language};
+use crate::matrix::Matrix;
+use crate::openobserve::{OpenObserve, UserEvent};
+use crate::telegram::*;
+use crate::welcome::escape_html;
+
+// Everything an update handler needs, shared by the poller and the webhook server
+#[derive(Clone)]
+pub struct AppState {
+    pub telegram_client: Arc<Telegram>,
+    pub challenge_state: ChallengeState,
+    pub bot_config_state: BotConfigState,
+    pub open_client: Option<Arc<OpenObserve>>,
+    pub matrix_client: Option<Arc<Matrix>>,
+    pub start_time: Instant,
+}
+
+// --- Concurrent dispatch ---
+//
+// Updates of different chats are handled in parallel, up to MAX_CONCURRENT_UPDATES
+// at a time, while updates of the same chat go through a per-chat queue and are
+// handled one after another in arrival order. A chat's worker exits as soon as
+// its queue is drained, so idle chats cost nothing.
+
+pub struct Dispatcher {
+    app: AppState,
+    permits: Arc<Semaphore>,
+    queues: std::sync::Mutex<HashMap<i64, mpsc::UnboundedSender<Update>>>,
+}
+
+impl Dispatcher {
+    pub fn new(app: AppState, max_concurrent_updates: usize) -> Arc<Self> {
+        // Requests can learn of an upgrade before, or instead of, its service message
+        tokio::spawn(follow_chat_migrations(
+            app.telegram_client.clone(),
+            app.bot_config_state.clone(),
+            app.challenge_state.clone(),
+            app.telegram_client.subscribe_chat_migrations(),
+        ));
+        Arc::new(Dispatcher {
+            app,
+            permits: Arc::new(Semaphore::new(max_concurrent_updates.max(1))),
+            queues: std::sync::Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn from_env(app: AppState) -> Arc<Self> {
+        let max_concurrent_updates = env::var("MAX_CONCURRENT_UPDATES")
+            .unwrap_or_else(|_| "16".to_string())
+            .parse::<usize>()
+            .unwrap_or(16);
+        debug!(
+            "Handling up to {} updates at a time",
+            max_concurrent_updates
+        );
+        Self::new(app, max_concurrent_updates)
+    }
+
+    // Queues the update behind the previous ones of its chat and returns immediately
+    pub fn dispatch(self: &Arc<Self>, update: Update) {
+        let Some(chat_id) = update_chat_id(&update) else {
+            let dispatcher = self.clone();
+            tokio::spawn(async move { dispatcher.run(update).await });
+            return;
+        };
+
+        let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+        let update = match queues.get(&chat_id) {
+            Some(queue) => match queue.send(update) {
+                Ok(()) => return,
+                Err(mpsc::error::SendError(update)) => update,
+            },
+            None => update,
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let _ = tx.send(update);
+        queues.insert(chat_id, tx);
+        let dispatcher = self.clone();
+        tokio::spawn(async move { dispatcher.run_chat_queue(chat_id, rx).await });
+    }
+
+    async fn run_chat_queue(&self, chat_id: i64, mut rx: mpsc::UnboundedReceiver<Update>) {
+        loop {
+            while let Ok(update) = rx.try_recv() {
+                self.run(update).await;
+            }
+            // dispatch() only sends while holding this lock, so nothing can slip
+            // in between the emptiness check and the removal
+            let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+            if rx.is_empty() {
+                queues.remove(&chat_id);
+                return;
+            }
+        }
+    }
+
+    async fn run(&self, update: Update) {
+        let Ok(_permit) = self.permits.acquire().await else {
+            return;
+        };
+        handle_update(update, &self.app).await;
+    }
+}
+
+// Chat whose updates must keep their order, if the update belongs to one
+fn update_chat_id(update: &Update) -> Option<i64> {
+    if let Some(message) = &update.message {
+        return Some(message.chat.id);
+    }
+    if let Some(callback_query) = &update.callback_query {
+        return callback_query
+            .message
+            .as_ref()
+            .map(|message| message.chat.id);
+    }
+    if let Some(chat_member) = &update.chat_member {
+        return Some(chat_member.chat.id);
+    }
+    if let Some(my_chat_member) = &update.my_chat_member {
+        return Some(my_chat_member.chat.id);
+    }
+    update
+        .chat_join_request
+        .as_ref()
+        .map(|join_request| join_request.chat.id)
+}
+
+pub async fn handle_update(update: Update, app: &AppState) {
+    let AppState {
+        telegram_client,
+        challenge_state,
+        bot_config_state,
+        open_client,
+        matrix_client,
+        start_time,
+    } = app;
+
+    if let Some(message) = update.message {
+        // Telegram posts a service message in both chats when a group becomes a supergroup
+        let migration = match (message.migrate_to_chat_id, message.migrate_from_chat_id) {
+            (Some(to_chat_id), _) => Some((message.chat.id, to_chat_id)),
+            (None, Some(from_chat_id)) => Some((from_chat_id, message.chat.id)),
+            (None, None) => None,
+        };
+        if let Some((from_chat_id, to_chat_id)) = migration {
+            migrate_chat(
+                telegram_client,
+                bot_config_state,
+                challenge_state,
+                from_chat_id,
+                to_chat_id,
+            )
+            .await;
+            return;
+        }
+
+        // Check for new chat members in all possible fields
+        // Use HashSet to avoid processing the same user multiple times
+        let mut new_users_to_process: std::collections::HashSet<i64> =
+            std::collections::HashSet::new();
+        let mut user_data_map: std::collections::HashMap<i64, &User> =
+            std::collections::HashMap::new();
+
+        // Collect from new_chat_members (plural)
+        if let Some(new_members) = &message.new_chat_members {
+            for new_member in new_members {
+                debug!(
+                    "Detected new member via new_chat_members: {} (ID: {}, is_bot: {})",
+                    new_member.first_name, new_member.id, new_member.is_bot
+                );
+                user_data_map.insert(new_member.id, new_member);
+
+                if !new_member.is_bot {
+                    new_users_to_process.insert(new_member.id);
+                } else {
+                    // Verificar variable de entorno para el tratamiento de bots
+                    let ban_bots_directly = env::var("BAN_BOTS_DIRECTLY")
+                        .unwrap_or_else(|_| "true".to_string())
+                        .to_lowercase()
+                        == "true";
+
+                    if ban_bots_directly {
+                        // Verificar lista blanca
+                        let config =
+                            get_or_create_bot_config(bot_config_state, message.chat.id).await;
+                        if config.whitelisted_bots.contains(&new_member.id) {
+                            debug!(
+                                "Bot {} está en la lista blanca, permitiendo acceso",
+                                new_member.first_name
+                            );
+                        } else {
+                            debug!(
+                                "Bot detected: {} - expulsando automáticamente",
+                                new_member.first_name
+                            );
+
+                            if let Err(e) = telegram_client
+                                .ban_chat_member(message.chat.id, new_member.id)
+                                .await
+                            {
+                                error!("Failed to ban bot {}: {}", new_member.id, e);
+                            } else {
+                                debug!("Bot {} expulsado exitosamente", new_member.first_name);
+
+                                // Actualizar estadísticas y notificar
+                                {
+                                    let config = update_bot_config(
+                                        bot_config_state,
+                                        message.chat.id,
+                                        |config| {
+                                            config.banned_bots_count += 1;
+                                            config.clone()
+                                        },
+                                    )
+                                    .await;
+
+                                    if config.notify_on_ban {
+                                        let notification_msg = tr!(
+                                            config.language,
+                                            "result.bot_banned",
+                                            name = escape_html(&new_member.first_name),
+                                            id = new_member.id,
+                                            total = config.banned_bots_count
+                                        );
+                                        if let Err(e) = telegram_client
+                                            .send_message(
+                                                message.chat.id,
+                                                challenge_topic(bot_config_state, &message.chat)
+                                                    .await,
+                                                &notification_msg,
+                                            )
+                                            .await
+                                        {
+                                            error!("Failed to send ban notification: {}", e);
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    } else {
+                        // Tratar bot como usuario normal - aplicar challenge
+                        debug!(
+                            "Bot detected: {} - aplicando challenge como usuario normal",
+                            new_member.first_name
+                        );
+                        new_users_to_process.insert(new_member.id);
+                    }
+                }
+            }
+        }
+
+        // Also check new_chat_member (singular) - only if not already processed
+        if let Some(new_member) = &message.new_chat_member {
+            if let Entry::Vacant(entry) = user_data_map.entry(new_member.id) {
+                debug!(
+                    "Detected new member via new_chat_member: {} (ID: {}, is_bot: {})",
+                    new_member.first_name, new_member.id, new_member.is_bot
+                );
+                entry.insert(new_member);
+
+                if !new_member.is_bot {
+                    new_users_to_process.insert(new_member.id);
+                } else {
+                    // Verificar variable de entorno para el tratamiento de bots
+                    let ban_bots_directly = env::var("BAN_BOTS_DIRECTLY")
+                        .unwrap_or_else(|_| "true".to_string())
+                        .to_lowercase()
+                        == "true";
+
+                    if ban_bots_directly {
+                        // Verificar lista blanca
+                        let config =
+                            get_or_create_bot_config(bot_config_state, message.chat.id).await;
+                        if config.whitelisted_bots.contains(&new_member.id) {
+                            debug!(
+                                "Bot {} está en la lista blanca, permitiendo acceso",
+                                new_member.first_name
+                            );
+                        } else {
+                            debug!(
+                                "Bot detected: {} - expulsando automáticamente",
+                                new_member.first_name
+                            );
+                            if let Err(e) = telegram_client
+                                .ban_chat_member(message.chat.id, new_member.id)
+                                .await
+                            {
+                                error!("Failed to ban bot {}: {}", new_member.id, e);
+                            } else {
+                                debug!("Bot {} expulsado exitosamente", new_member.first_name);
+                            }
+                        }
+                    } else {
+                        // Tratar bot como usuario normal - aplicar challenge
+                        debug!(
+                            "Bot detected: {} - aplicando challenge como usuario normal",
+                            new_member.first_name
+                        );
+                        new_users_to_process.insert(new_member.id);
+                    }
+                }
+            } else {
+                debug!(
+                    "User {} already processed from new_chat_members, skipping new_chat_member",
+                    new_member.id
+                );
+            }
+        }
+
+        // Also check new_chat_participant (alternative field) - only if not already processed
+        if let Some(new_participant) = &message.new_chat_participant {
+            if let Entry::Vacant(entry) = user_data_map.entry(new_participant.id) {
+                debug!(
+                    "Detected new member via new_chat_participant: {} (ID: {}, is_bot: {})",
+                    new_participant.first_name, new_participant.id, new_participant.is_bot
+                );
+                entry.insert(new_participant);
+
+                if !new_participant.is_bot {
+                    new_users_to_process.insert(new_participant.id);
+                } else {
+                    // Verificar variable de entorno para el tratamiento de bots
+                    let ban_bots_directly = env::var("BAN_BOTS_DIRECTLY")
+                        .unwrap_or_else(|_| "true".to_string())
+                        .to_lowercase()
+                        == "true";
+
+                    if ban_bots_directly {
+                        // Verificar lista blanca
+                        let config =
+                            get_or_create_bot_config(bot_config_state, message.chat.id).await;
+                        if config.whitelisted_bots.contains(&new_participant.id) {
+                            debug!(
+                                "Bot {} está en la lista blanca, permitiendo acceso",
+                                new_participant.first_name
+                            );
+                        } else {
+                            debug!(
+                                "Bot detected: {} - expulsando automáticamente",
+                                new_participant.first_name
+                            );
+                            if let Err(e) = telegram_client
+                                .ban_chat_member(message.chat.id, new_participant.id)
+                                .await
+                            {
+                                error!("Failed to ban bot {}: {}", new_participant.id, e);
+                            } else {
+                                debug!("Bot {} expulsado exitosamente", new_participant.first_name);
+                            }
+                        }
+                    } else {
+                        // Tratar bot como usuario normal - aplicar challenge
+                        debug!(
+                            "Bot detected: {} - aplicando challenge como usuario normal",
+                            new_participant.first_name
+                        );
+                        new_users_to_process.insert(new_participant.id);
+                    }
+                }
+            } else {
+                debug!(
+                    "User {} already processed from previous fields, skipping new_chat_participant",
+                    new_participant.id
+                );
+            }
+        }
+
+        // Process all collected new users (now without duplicates)
+        for user_id in new_users_to_process {
+            if let Some(user_data) = user_data_map.get(&user_id) {
+                debug!(
+                    "Processing unique new member: User ID {} in chat {}",
+                    user_id, message.chat.id
+                );
+                if let Err(e) = process_new_member(
+                    telegram_client.clone(),
+                    message.chat.id,
+                    user_data,
+                    message.chat.title.clone(),
+                    challenge_topic(bot_config_state, &message.chat).await,
+                    challenge_state,
+                    bot_config_state,
+                    open_client.clone(),
+                    matrix_client.clone(),
+                )
+                .await
+                {
+                    error!("Failed to process new member {}: {}", user_id, e);
+                }
+            }
+        }
+
+        // Process text messages
+        let message_thread_id = message.topic_id();
+        if let Some(text) = message.text {
+            debug!(
+                "Received message in chat {}: '{}' from user {}",
+                message.chat.id, text, message.from.first_name
+            );
+
+            // Check if message is a command
+            if text.starts_with("/") {
+                // Handle command using the dedicated commands module
+                if let Err(e) = handle_command(
+                    &text,
+                    &message.chat,
+                    message_thread_id,
+                    &message.from,
+                    telegram_client,
+                    bot_config_state,
+                    challenge_state,
+                    start_time,
+                )
+                .await
+                {
+                    debug!("Command handling failed: {}", e);
+                }
+            }
+        }
+    } else if let Some(chat_member_update) = update.chat_member {
+        debug!("Chat Member Update received: {:?}", chat_member_update);
+
+        // Only real joins: restricted -> member also happens when a user passes the challenge
+        let old_status = chat_member_update.old_chat_member.status.as_str();
+        let new_status = chat_member_update.new_chat_member.status.as_str();
+        if (old_status == "left" || old_status == "kicked")
+            && (new_status == "member" || new_status == "restricted")
+        {
+            let user_id = chat_member_update.new_chat_member.user.id;
+            let chat_id = chat_member_update.chat.id;
+
+            if let Err(e) = process_new_member(
+                telegram_client.clone(),
+                chat_id,
+                &chat_member_update.new_chat_member.user,
+                chat_member_update.chat.title.clone(),
+                challenge_topic(bot_config_state, &chat_member_update.chat).await,
+                challenge_state,
+                bot_config_state,
+                open_client.clone(),
+                matrix_client.clone(),
+            )
+            .await
+            {
+                error!("Failed to process new member {}: {}", user_id, e);
+            }
+        }
+    } else if let Some(join_request) = update.chat_join_request {
+        debug!("Chat Join Request received: {:?}", join_request);
+        let user_id = join_request.from.id;
+        if let Err(e) = process_join_request(
+            telegram_client.clone(),
+            &join_request,
+            challenge_state,
+            bot_config_state,
+            open_client.clone(),
+            matrix_client.clone(),
+        )
+        .await
+        {
+            error!("Failed to process join request of user {}: {}", user_id, e);
+        }
+    } else if let Some(my_chat_member) = update.my_chat_member {
+        debug!("My Chat Member Update received: {:?}", my_chat_member);
+        process_my_chat_member(
+            telegram_client.clone(),
+            &my_chat_member,
+            challenge_state,
+            bot_config_state,
+        )
+        .await;
+    } else if let Some(callback_query) = update.callback_query {
+        debug!(
+            "Callback query received: ID {}, From: {:?}",
+            callback_query.id,
+            callback_query
+                .from
+                .username
+                .as_ref()
+                .map_or(&callback_query.from.first_name, |u| u)
+        );
+        if let (Some(message), Some(selected_option)) =
+            (callback_query.message, callback_query.data)
+        {
+            let user_id = callback_query.from.id;
+            let mut chat_id = message.chat.id;
+            let mut state_guard = challenge_state.lock().await;
+
+            if message.chat.chat_type == "private" {
+                if let Some(group_id) = ChallengeState::find_private_challenge_chat(
+                    &state_guard,
+                    chat_id,
+                    user_id,
+                    message.message_id,
+                ) {
+                    chat_id = group_id;
+                }
+            }
+
+            // Only the challenged user may answer, and only on their own challenge message
+            let is_own_challenge = state_guard
+                .get(&chat_id)
+                .and_then(|chat_challenges| chat_challenges.get(&user_id))
+                .is_some_and(|challenge| challenge.challenge_message_id == message.message_id);
+            if !is_own_challenge {
+                let is_someone_elses = state_guard.get(&chat_id).is_some_and(|chat_challenges| {
+                    chat_challenges
+                        .values()
+                        .any(|challenge| challenge.challenge_message_id == message.message_id)
+                });
+                drop(state_guard);
+                debug!(
+                    "User {} clicked a challenge that is not theirs in chat {} (active: {})",
+                    user_id, chat_id, is_someone_elses
+                );
+                // The clicker may not be the challenged user, so speak their language
+                let language = Language::for_user(
+                    callback_query.from.language_code.as_deref(),
+                    chat_language(bot_config_state, chat_id).await,
+                );
+                let text = if is_someone_elses {
+                    tr!(language, "answer.not_yours")
+                } else {
+                    tr!(language, "answer.not_active")
+                };
+                answer_callback(
+                    telegram_client,
+                    &callback_query.id,
+                    Some(&text),
+                    is_someone_elses,
+                )
+                .await;
+                return;
+            }
+
+            // The lock covers every chat, so it is never held across a request: the
+            // challenge is claimed here and the outcome applied without it
+            let min_response_time = challenge_state.too_fast_threshold(chat_id).await;
+            let Some(challenge) = state_guard
+                .get_mut(&chat_id)
+                .and_then(|chat_challenges| chat_challenges.get_mut(&user_id))
+            else {
+                return;
+            };
+            // Check if user responded too quickly (potential bot)
+            let response_time = challenge.start_time.elapsed();
+            let too_fast = response_time < min_response_time;
+            let correct = challenge.puzzle.verify(&selected_option);
+            let language = challenge.language;
+
+            // A wrong answer only fails the challenge once no attempts are left
+            if !too_fast && !correct && challenge.attempts_left > 1 {
+                challenge.attempts_left -= 1;
+                let attempts_left = challenge.attempts_left;
+                challenge_state.persist(&state_guard).await;
+                drop(state_guard);
+                debug!(
+                    "User {} selected a wrong answer in chat {}, {} attempts left",
+                    user_id, chat_id, attempts_left
+                );
+                let toast = tr!(
+                    language,
+                    "answer.wrong_retry",
+                    attempts_left = attempts_left_text(language, attempts_left)
+                );
+                answer_callback(telegram_client, &callback_query.id, Some(&toast), false).await;
+                retry_challenge(
+                    telegram_client,
+                    bot_config_state,
+                    challenge_state,
+                    chat_id,
+                    user_id,
+                )
+                .await;
+                return;
+            }
+
+            // Once claimed, the timeout finds nothing left to resolve
+            let Some(challenge) =
+                ChallengeState::remove_challenge(&mut state_guard, chat_id, user_id)
+            else {
+                return;
+            };
+            drop(state_guard);
+            if !too_fast && correct {
+                challenge_state
+                    .record_response_time(chat_id, response_time)
+                    .await;
+            }
+
+            // Stop the client's spinner before the slower ban/unrestrict calls
+            let toast = if too_fast {
+                tr!(language, "answer.too_fast")
+            } else if correct {
+                tr!(language, "answer.correct")
+            } else {
+                tr!(language, "answer.wrong")
+            };
+            answer_callback(telegram_client, &callback_query.id, Some(&toast), false).await;
+
+            if challenge.join_request_chat_id.is_some() {
+                let approved = !too_fast && correct;
+                debug!(
+                    "User {} answered the join request challenge of chat {} (approved: {})",
+                    user_id, chat_id, approved
+                );
+                resolve_join_request(
+                    telegram_client,
+                    chat_id,
+                    user_id,
+                    &challenge,
+                    approved,
+                    challenge_state,
+                    open_client,
+                    matrix_client,
+                )
+                .await;
+
+                let _ = challenge.tx.send(());
+            } else if too_fast {
+                debug!(
+                    "User {} responded too quickly ({:?} < {:?}) in chat {} - treating as bot",
+                    user_id, response_time, min_response_time, chat_id
+                );
+
+                let sanction =
+                    failure_sanction(bot_config_state, challenge_state, chat_id, user_id).await;
+                let penalty = sanction.penalty;
+                if let Err(e) = sanction.apply(telegram_client, chat_id, user_id).await {
+                    error!(
+                        "Failed to apply penalty to user {} for quick response in chat {}: {}",
+                        user_id, chat_id, e
+                    );
+                } else {
+                    announce_outcome(
+                        telegram_client,
+                        chat_id,
+                        &challenge,
+                        &tr!(language, "result.too_fast"),
+                    )
+                    .await;
+
+                    // Send event to OpenObserve
+                    if let Some(open_observe_client) = &open_client {
+                        let event = UserEvent {
+                            user_id,
+                            user_name: callback_query.from.first_name.clone(),
+                            group_id: chat_id,
+                            group_name: message
+                                .chat
+                                .title
+                                .as_deref()
+                                .unwrap_or("Unknown Group")
+                                .to_string(),
+                            challenge_completed: false,
+                            banned: penalty.removes_member(),
+                            strike: sanction.strike,
+                        };
+                        if let Err(e) = open_observe_client.send_user_event(&event).await {
+                            error!("Failed to send user event to OpenObserve: {:?}", e);
+                        }
+                    }
+
+                    // Send message to Matrix
+                    if let Some(matrix_client) = &matrix_client {
+                        let matrix_message = format!(
+                            "el usuario {} con id {} respondió demasiado rápido ({:?}) y fue {} del grupo {} con id {} por comportamiento de bot{}",
+                            callback_query.from.first_name,
+                            user_id,
+                            response_time,
+                            penalty.log_description(),
+                            message.chat.title.as_deref().unwrap_or("Unknown Group"),
+                            chat_id,
+                            sanction.strike_note()
+                        );
+                        if let Err(e) = matrix_client.send_message(&matrix_message).await {
+                            error!("Failed to send message to Matrix: {:?}", e);
+                        }
+                    }
+                }
+
+                let _ = challenge.tx.send(());
+            } else if correct {
+                debug!(
+                    "User {} selected the correct answer '{}' in chat {}",
+                    user_id, selected_option, chat_id
+                );
+
+                if restore_member_permissions(telegram_client, bot_config_state, chat_id, user_id)
+                    .await
+                    .is_err()
+                {
+                    error!(
+                        "Failed to unrestrict chat member {} in chat {}",
+                        user_id, chat_id
+                    );
+                    let outcome = tr!(
+                        language,
+                        "result.restore_failed",
+                        name = escape_html(&callback_query.from.first_name)
+                    );
+                    announce_outcome(telegram_client, chat_id, &challenge, &outcome).await;
+                } else {
+                    debug!("Permissions granted for user {}", user_id);
+                    let welcome = get_or_create_bot_config(bot_config_state, chat_id)
+                        .await
+                        .welcome;
+                    announce_welcome(telegram_client, chat_id, &challenge, user_id, &welcome).await;
+                }
+
+                // Send success event to OpenObserve
+                if let Some(open_client) = &open_client {
+                    let event = UserEvent {
+                        user_id,
+                        user_name: callback_query.from.first_name.clone(),
+                        group_id: chat_id,
+                        group_name: message
+                            .chat
+                            .title
+                            .as_deref()
+                            .unwrap_or("Unknown Group")
+                            .to_string(),
+                        challenge_completed: true,
+                        banned: false,
+                        strike: None,
+                    };
+                    if let Err(e) = open_client.send_user_event(&event).await {
+                        error!("Failed to send user event to OpenObserve: {:?}", e);
+                    }
+                }
+
+                // Send message to Matrix
+                if let Some(matrix_client) = &matrix_client {
+                    let matrix_message = format!(
+                        "el usuario {} con id {} si superó el challenge y no fue baneado del grupo {} con id {}",
+                        callback_query.from.first_name,
+                        user_id,
+                        message.chat.title.as_deref().unwrap_or("Unknown Group"),
+                        chat_id
+                    );
+                    if let Err(e) = matrix_client.send_message(&matrix_message).await {
+                        error!("Failed to send message to Matrix: {:?}", e);
+                    }
+                }
+
+                let _ = challenge.tx.send(());
+            } else {
+                debug!(
+                    "User {} selected the wrong answer '{}' in chat {}",
+                    user_id, selected_option, chat_id
+                );
+
+                let sanction =
+                    failure_sanction(bot_config_state, challenge_state, chat_id, user_id).await;
+                let penalty = sanction.penalty;
+                if let Err(e) = sanction.apply(telegram_client, chat_id, user_id).await {
+                    error!(
+                        "Failed to apply penalty to user {} after incorrect answer: {}",
+                        user_id, e
+                    );
+                } else {
+                    announce_outcome(
+                        telegram_client,
+                        chat_id,
+                        &challenge,
+                        &tr!(language, "result.failed"),
+                    )
+                    .await;
+
+                    // Send failure event to OpenObserve
+                    if let Some(open_client) = &open_client {
+                        let event = UserEvent {
+                            user_id,
+                            user_name: callback_query.from.first_name.clone(),
+                            group_id: chat_id,
+                            group_name: message
+                                .chat
+                                .title
+                                .as_deref()
+                                .unwrap_or("Unknown Group")
+                                .to_string(),
+                            challenge_completed: false,
+                            banned: penalty.removes_member(),
+                            strike: sanction.strike,
+                        };
+                        if let Err(e) = open_client.send_user_event(&event).await {
+                            error!("Failed to send user event to OpenObserve: {:?}", e);
+                        }
+                    }
+
+                    // Send message to Matrix
+                    if let Some(matrix_client) = &matrix_client {
+                        let matrix_message = format!(
+                            "el usuario {} con id {} no superó el challenge y fue {} del grupo {} con id {}{}",
+                            callback_query.from.first_name,
+                            user_id,
+                            penalty.log_description(),
+                            message.chat.title.as_deref().unwrap_or("Unknown Group"),
+                            chat_id,
+                            sanction.strike_note()
+                        );
+                        if let Err(e) = matrix_client.send_message(&matrix_message).await {
+                            error!("Failed to send message to Matrix: {:?}", e);
+                        }
+                    }
+                }
+
+                let _ = challenge.tx.send(());
+            }
+
+            challenge_state.sync().await;
+        } else {
+            answer_callback(telegram_client, &callback_query.id, None, false).await;
+        }
+    }
+}
+
+async fn answer_callback(
+    telegram_client: &Arc<Telegram>,
+    callback_query_id: &str,
+    text: Option<&str>,
+    show_alert: bool,
+) {
+    if let Err(e) = telegram_client
+        .answer_callback_query(callback_query_id, text, show_alert)
+        .await
+    {
+        debug!(
+            "Failed to answer callback query {}: {}",
+            callback_query_id, e
+        );
+    }
+}
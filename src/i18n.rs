@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::OnceLock;
+use tracing::warn;
+
+// --- Message catalogs ---
+//
+// Every user-visible string lives in locales/<code>.toml, embedded at build time.
+// Tables nest into dotted keys ("commands.start") and `{name}` placeholders are
+// filled in by `tr!`. A key missing from a catalog falls back to Spanish.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    Es,
+    En,
+    Ca,
+}
+
+impl Language {
+    pub const ALL: &'static [Language] = &[Language::Es, Language::En, Language::Ca];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Language::Es => "es",
+            Language::En => "en",
+            Language::Ca => "ca",
+        }
+    }
+
+    pub fn native_name(self) -> &'static str {
+        match self {
+            Language::Es => "Español",
+            Language::En => "English",
+            Language::Ca => "Català",
+        }
+    }
+
+    // Accepts IETF tags as Telegram sends them ("en", "en-GB", "pt-br")
+    pub fn from_code(code: &str) -> Option<Self> {
+        let primary = code.split(['-', '_']).next().unwrap_or_default();
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|language| language.code().eq_ignore_ascii_case(primary))
+    }
+
+    // The user's own language when there is a catalog for it, else `fallback`
+    pub fn for_user(language_code: Option<&str>, fallback: Language) -> Self {
+        language_code.and_then(Self::from_code).unwrap_or(fallback)
+    }
+
+    fn catalog_source(self) -> &'static str {
+        match self {
+            Language::Es => include_str!("../locales/es.toml"),
+            Language::En => include_str!("../locales/en.toml"),
+            Language::Ca => include_str!("../locales/ca.toml"),
+        }
+    }
+}
+
+type Catalog = HashMap<String, String>;
+
+fn flatten(prefix: &str, table: toml::Table, catalog: &mut Catalog) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(table) => flatten(&key, table, catalog),
+            toml::Value::String(text) => {
+                catalog.insert(key, text);
+            }
+            value => warn!("Ignoring non-string catalog entry {} = {}", key, value),
+        }
+    }
+}
+
+fn catalogs() -> &'static HashMap<Language, Catalog> {
+    static CATALOGS: OnceLock<HashMap<Language, Catalog>> = OnceLock::new();
+    CATALOGS.get_or_init(|| {
+        Language::ALL
+            .iter()
+            .map(|&language| {
+                let mut catalog = Catalog::new();
+                // The catalogs ship with the binary and are checked by the tests
+                let table: toml::Table = language
+                    .catalog_source()
+                    .parse()
+                    .unwrap_or_else(|e| panic!("invalid {} catalog: {}", language.code(), e));
+                flatten("", table, &mut catalog);
+                (language, catalog)
+            })
+            .collect()
+    })
+}
+
+fn lookup(language: Language, key: &str) -> String {
+    let catalogs = catalogs();
+    match catalogs[&language]
+        .get(key)
+        .or_else(|| catalogs[&Language::default()].get(key))
+    {
+        Some(text) => text.clone(),
+        None => {
+            warn!("Missing message {} in every catalog", key);
+            key.to_string()
+        }
+    }
+}
+
+// Use through tr!, which builds the argument list
+pub fn tr_with(language: Language, key: &str, args: &[(&str, &(dyn Display + Sync))]) -> String {
+    args.iter()
+        .fold(lookup(language, key), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), &value.to_string())
+        })
+}
+
+// tr!(language, "key") or tr!(language, "key", name = value, ...), where the
+// values can be anything Display
+macro_rules! tr {
+    ($language:expr, $key:expr $(,)?) => {
+        $crate::i18n::tr_with($language, $key, &[])
+    };
+    ($language:expr, $key:expr, $($name:ident = $value:expr),+ $(,)?) => {
+        $crate::i18n::tr_with(
+            $language,
+            $key,
+            &[$((stringify!($name), &$value as &(dyn std::fmt::Display + Sync))),+],
+        )
+    };
+}
+pub(crate) use tr;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn placeholders(text: &str) -> BTreeSet<&str> {
+        text.split('{')
+            .skip(1)
+            .filter_map(|rest| rest.split_once('}').map(|(name, _)| name))
+            .collect()
+    }
+
+    #[test]
+    fn test_catalogs_match_the_spanish_one() {
+        let catalogs = catalogs();
+        let spanish = &catalogs[&Language::Es];
+        for language in Language::ALL {
+            let catalog = &catalogs[language];
+            for (key, text) in spanish {
+                let translation = catalog
+                    .get(key)
+                    .unwrap_or_else(|| panic!("{} is missing {}", language.code(), key));
+                assert_eq!(
+                    placeholders(translation),
+                    placeholders(text),
+                    "placeholders of {} in {}",
+                    key,
+                    language.code()
+                );
+            }
+            assert_eq!(
+                catalog.len(),
+                spanish.len(),
+                "extra keys in {}",
+                language.code()
+            );
+        }
+    }
+
+    #[test]
+    fn test_language_selection_and_placeholders() {
+        assert_eq!(Language::from_code("en-GB"), Some(Language::En));
+        assert_eq!(Language::from_code("CA"), Some(Language::Ca));
+        assert_eq!(Language::from_code("pt-br"), None);
+        assert_eq!(Language::for_user(Some("de"), Language::Ca), Language::Ca);
+        assert_eq!(Language::for_user(None, Language::En), Language::En);
+
+        assert_eq!(
+            tr!(Language::En, "answer.wrong_retry", attempts_left = 2),
+            "❌ Wrong answer. 2"
+        );
+        assert_eq!(tr!(Language::Ca, "no.such.key"), "no.such.key");
+    }
+}
@@ -42,7 +42,7 @@ impl Matrix {
             Self::ts(),
         );
         debug!("Url: {}", url);
-        let parser = Parser::new(&markdown);
+        let parser = Parser::new(markdown);
         let mut html = String::new();
         push_html(&mut html, parser);
         debug!("Post with matrix: {}\n{}", markdown, html);
@@ -0,0 +1,431 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, RawQuery, State},
+    http::{header, HeaderMap},
+    routing::any,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration as RealDuration;
+use tokio::sync::Notify;
+use tokio::time::Duration;
+
+use crate::ratelimit::RateLimiter;
+use crate::telegram::Telegram;
+
+// --- In-process fake of the Telegram Bot API for end-to-end tests ---
+//
+// Records every call with its payload, answers with plausible results and serves
+// scripted updates through a long-polling getUpdates, so the real client, poller
+// and dispatcher run unchanged against it.
+
+pub const TEST_TOKEN: &str = "123456:TEST";
+
+// Longest a test waits for an expected call, in real time
+const WAIT_LIMIT: RealDuration = RealDuration::from_secs(10);
+
+// With a paused clock tokio jumps to the next timer whenever the runtime looks idle,
+// which includes waiting for a response on a real socket: a challenge would expire
+// while its own sendMessage is in flight. The runtime never auto-advances while a
+// blocking task runs, so holding one keeps the clock still until the test calls
+// tokio::time::advance.
+pub struct ClockHold {
+    _release: mpsc::Sender<()>,
+}
+
+impl ClockHold {
+    pub fn new() -> Self {
+        let (release, released) = mpsc::channel::<()>();
+        tokio::task::spawn_blocking(move || {
+            let _ = released.recv();
+        });
+        ClockHold { _release: release }
+    }
+}
+
+// Runs `future` for at most `limit` of real time, whatever the tokio clock does
+pub async fn within_real_time<F: Future>(limit: RealDuration, future: F) -> Option<F::Output> {
+    let (done, finished) = mpsc::channel::<()>();
+    let deadline = tokio::task::spawn_blocking(move || {
+        let _ = finished.recv_timeout(limit);
+    });
+    tokio::select! {
+        output = future => {
+            drop(done);
+            Some(output)
+        }
+        _ = deadline => None,
+    }
+}
+
+// Gives in-flight requests a moment of real time to make progress
+pub async fn settle() {
+    let _ = tokio::task::spawn_blocking(|| std::thread::sleep(RealDuration::from_millis(5))).await;
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiCall {
+    pub method: String,
+    pub payload: Value,
+}
+
+#[derive(Default)]
+struct MockState {
+    calls: Vec<ApiCall>,
+    updates: VecDeque<Value>,
+    next_update_id: u64,
+    next_message_id: u64,
+    admins: Vec<i64>,
+    failures: HashMap<String, VecDeque<Value>>, // Scripted error responses per method
+}
+
+#[derive(Clone)]
+pub struct MockBotApi {
+    url: String,
+    state: Arc<Mutex<MockState>>,
+    updates_queued: Arc<Notify>,
+    call_recorded: Arc<Notify>,
+}
+
+impl MockBotApi {
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind mock Bot API");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let api = MockBotApi {
+            url,
+            state: Arc::new(Mutex::new(MockState {
+                next_update_id: 1,
+                next_message_id: 1000,
+                ..MockState::default()
+            })),
+            updates_queued: Arc::new(Notify::new()),
+            call_recorded: Arc::new(Notify::new()),
+        };
+        let router = Router::new()
+            .route("/{token}/{method}", any(handle_call))
+            .with_state(api.clone());
+        tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        });
+        api
+    }
+
+    // Unpaced client: the rate limiter's sleeps would otherwise need the clock to move
+    pub fn telegram(&self) -> Telegram {
+        Telegram::new(TEST_TOKEN, &self.url)
+            .with_rate_limiter(RateLimiter::new(Duration::ZERO, Duration::ZERO))
+    }
+
+    // Queues an update for the next getUpdates; the update_id is filled in
+    pub fn push_update(&self, mut update: Value) {
+        let mut state = self.state.lock().unwrap();
+        update["update_id"] = json!(state.next_update_id);
+        state.next_update_id += 1;
+        state.updates.push_back(update);
+        drop(state);
+        self.updates_queued.notify_one();
+    }
+
+    pub fn set_admins(&self, admins: &[i64]) {
+        self.state.lock().unwrap().admins = admins.to_vec();
+    }
+
+    // Makes the next call to `method` fail with the given Bot API error
+    pub fn fail_next(&self, method: &str, error_code: i64, description: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .entry(method.to_string())
+            .or_default()
+            .push_back(json!({
+                "ok": false,
+                "error_code": error_code,
+                "description": description,
+            }));
+    }
+
+    // Makes the next call to `method` fail with a flood error asking to wait `retry_after` seconds
+    pub fn rate_limit_next(&self, method: &str, retry_after: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .entry(method.to_string())
+            .or_default()
+            .push_back(json!({
+                "ok": false,
+                "error_code": 429,
+                "description": format!("Too Many Requests: retry after {}", retry_after),
+                "parameters": { "retry_after": retry_after },
+            }));
+    }
+
+    // Makes the next call to `method` fail as if its group had been upgraded to a supergroup
+    pub fn migrate_next(&self, method: &str, new_chat_id: i64) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .entry(method.to_string())
+            .or_default()
+            .push_back(json!({
+                "ok": false,
+                "error_code": 400,
+                "description": "Bad Request: group chat was upgraded to a supergroup chat",
+                "parameters": { "migrate_to_chat_id": new_chat_id },
+            }));
+    }
+
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        self.state
+            .lock()
+            .unwrap()
+            .calls
+            .iter()
+            .filter(|call| call.method == method)
+            .map(|call| call.payload.clone())
+            .collect()
+    }
+
+    pub fn all_calls(&self) -> Vec<ApiCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    // Waits until `method` has been called `count` times and returns that call's payload
+    pub async fn wait_for_calls(&self, method: &str, count: usize) -> Value {
+        let wait = async {
+            loop {
+                let recorded = self.call_recorded.notified();
+                if let Some(payload) = self.calls(method).into_iter().nth(count - 1) {
+                    return payload;
+                }
+                recorded.await;
+            }
+        };
+        match within_real_time(WAIT_LIMIT, wait).await {
+            Some(payload) => payload,
+            None => panic!(
+                "{} was not called {} times; calls: {:?}",
+                method,
+                count,
+                self.all_calls()
+            ),
+        }
+    }
+
+    pub async fn wait_for_call(&self, method: &str) -> Value {
+        self.wait_for_calls(method, 1).await
+    }
+
+    fn record(&self, method: &str, payload: Value) -> Option<Value> {
+        let mut state = self.state.lock().unwrap();
+        state.calls.push(ApiCall {
+            method: method.to_string(),
+            payload: payload.clone(),
+        });
+        let failure = state
+            .failures
+            .get_mut(method)
+            .and_then(|failures| failures.pop_front());
+        drop(state);
+        self.call_recorded.notify_waiters();
+        failure
+    }
+
+    async fn next_updates(&self) -> Vec<Value> {
+        loop {
+            let queued = self.updates_queued.notified();
+            let updates: Vec<Value> = self.state.lock().unwrap().updates.drain(..).collect();
+            if !updates.is_empty() {
+                return updates;
+            }
+            queued.await;
+        }
+    }
+
+    fn result_for(&self, method: &str, payload: &Value) -> Value {
+        match method {
+            "sendMessage" | "sendPhoto" => {
+                let mut state = self.state.lock().unwrap();
+                state.next_message_id += 1;
+                json!({
+                    "message_id": state.next_message_id,
+                    "chat": { "id": payload["chat_id"], "type": "supergroup" },
+                    "date": 0,
+                })
+            }
+            "getMe" => json!({
+                "id": 1,
+                "is_bot": true,
+                "first_name": "ExpulsaBot",
+                "username": "expulsa_test_bot",
+            }),
+            "getChat" => json!({
+                "id": payload["chat_id"],
+                "type": "supergroup",
+                "permissions": {
+                    "can_send_messages": true,
+                    "can_send_photos": true,
+                    "can_send_polls": false,
+                },
+            }),
+            "getChatMember" => {
+                let user_id = payload["user_id"].as_i64().unwrap_or_default();
+                let status = if self.state.lock().unwrap().admins.contains(&user_id) {
+                    "administrator"
+                } else {
+                    "member"
+                };
+                json!({
+                    "status": status,
+                    "user": { "id": user_id, "is_bot": false, "first_name": "Test" },
+                })
+            }
+            _ => json!(true),
+        }
+    }
+}
+
+// Turns a multipart upload into a JSON payload: text fields are decoded like the
+// JSON ones would be, files are recorded by name, type and size
+fn multipart_payload(content_type: &str, body: &[u8]) -> Value {
+    let Some((_, boundary)) = content_type.split_once("boundary=") else {
+        return Value::Null;
+    };
+    let delimiter = format!("--{}", boundary.trim_matches('"'));
+    let body = String::from_utf8_lossy(body);
+    let mut payload = json!({});
+    for part in body.split(delimiter.as_str()) {
+        let Some((headers, content)) = part.split_once("\r\n\r\n") else {
+            continue;
+        };
+        let content = content.strip_suffix("\r\n").unwrap_or(content);
+        let attribute = |name: &str| {
+            let start = headers.find(&format!("{}=\"", name))? + name.len() + 2;
+            let end = headers[start..].find('"')? + start;
+            Some(headers[start..end].to_string())
+        };
+        let Some(name) = attribute("name") else {
+            continue;
+        };
+        payload[&name] = match attribute("filename") {
+            Some(file_name) => json!({
+                "file_name": file_name,
+                "content_type": headers
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Type: ")),
+                "size": content.len(),
+            }),
+            None => serde_json::from_str(content).unwrap_or_else(|_| json!(content)),
+        };
+    }
+    payload
+}
+
+// Tags Telegram accepts in HTML mode
+const HTML_TAGS: &[&str] = &[
+    "b",
+    "strong",
+    "i",
+    "em",
+    "u",
+    "ins",
+    "s",
+    "strike",
+    "del",
+    "a",
+    "code",
+    "pre",
+    "tg-spoiler",
+    "blockquote",
+    "span",
+];
+
+// What Telegram would refuse to parse in an HTML-mode text: unknown or unbalanced
+// tags and a `&` that does not start an entity
+fn html_error(payload: &Value) -> Option<String> {
+    if payload["parse_mode"] != "HTML" {
+        return None;
+    }
+    let text = payload["text"].as_str().or(payload["caption"].as_str())?;
+    let mut open_tags = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(['<', '&']) {
+        rest = &rest[start..];
+        if rest.starts_with('&') {
+            let entity = rest[1..].split(';').next().unwrap_or_default();
+            let known = ["lt", "gt", "amp", "quot"].contains(&entity)
+                || entity.strip_prefix('#').is_some_and(|code| {
+                    !code.is_empty() && code.chars().all(|c| c.is_ascii_digit())
+                });
+            if !known || !rest.contains(';') {
+                return Some(format!("unsupported entity in {:?}", rest));
+            }
+            rest = &rest[entity.len() + 2..];
+            continue;
+        }
+        let Some(end) = rest.find('>') else {
+            return Some(format!("unclosed start tag in {:?}", rest));
+        };
+        let tag = &rest[1..end];
+        match tag.strip_prefix('/') {
+            Some(name) => {
+                if open_tags.pop() != Some(name.trim()) {
+                    return Some(format!("unmatched end tag \"{}\"", name));
+                }
+            }
+            None => {
+                let name = tag.split_whitespace().next().unwrap_or_default();
+                if !HTML_TAGS.contains(&name) {
+                    return Some(format!("unsupported start tag \"{}\"", name));
+                }
+                open_tags.push(name);
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    open_tags
+        .last()
+        .map(|name| format!("can't find end tag corresponding to start tag \"{}\"", name))
+}
+
+async fn handle_call(
+    State(api): State<MockBotApi>,
+    Path((_token, method)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Json<Value> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let payload = if body.is_empty() {
+        json!({ "query": query })
+    } else if content_type.starts_with("multipart/form-data") {
+        multipart_payload(content_type, &body)
+    } else {
+        serde_json::from_slice(&body).unwrap_or(Value::Null)
+    };
+
+    if let Some(failure) = api.record(&method, payload.clone()) {
+        return Json(failure);
+    }
+    if let Some(error) = html_error(&payload) {
+        return Json(json!({
+            "ok": false,
+            "error_code": 400,
+            "description": format!("Bad Request: can't parse entities: {}", error),
+        }));
+    }
+    if method == "getUpdates" {
+        return Json(json!({ "ok": true, "result": api.next_updates().await }));
+    }
+    Json(json!({ "ok": true, "result": api.result_for(&method, &payload) }))
+}
@@ -12,6 +12,8 @@ pub struct UserEvent {
     pub group_name: String,
     pub challenge_completed: bool,
     pub banned: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strike: Option<u32>, // Failures of the user so far, when the chat counts them
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,167 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::bot::unix_now;
+use crate::i18n::{tr, Language};
+use crate::telegram::{Telegram, TelegramError};
+
+// --- What happens to a user who fails a challenge ---
+
+// Telegram takes bans longer than 366 days (or shorter than 30 seconds) as permanent
+pub const MAX_TEMPBAN_MINUTES: u64 = 366 * 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Penalty {
+    #[default]
+    Ban,
+    Kick, // Banned and unbanned right away, so they can join again later
+    TempBan {
+        minutes: u64,
+    },
+    Mute, // Stays in the group with the restriction of the challenge
+}
+
+impl Penalty {
+    // The ladder of a chat counting strikes: a kick, then a day's ban, then for good
+    pub fn for_strike(strike: u32) -> Self {
+        match strike {
+            0 | 1 => Penalty::Kick,
+            2 => Penalty::TempBan { minutes: 24 * 60 },
+            _ => Penalty::Ban,
+        }
+    }
+
+    // Parses the arguments of /penalty: "ban", "kick", "mute" or "tempban <minutes>"
+    pub fn parse(args: &[&str]) -> Option<Self> {
+        match args {
+            ["ban"] => Some(Penalty::Ban),
+            ["kick"] => Some(Penalty::Kick),
+            ["mute"] => Some(Penalty::Mute),
+            ["tempban", minutes] => minutes
+                .parse::<u64>()
+                .ok()
+                .filter(|minutes| (1..=MAX_TEMPBAN_MINUTES).contains(minutes))
+                .map(|minutes| Penalty::TempBan { minutes }),
+            _ => None,
+        }
+    }
+
+    pub fn describe(self, language: Language) -> String {
+        match self {
+            Penalty::Ban => tr!(language, "commands.penalty_ban"),
+            Penalty::Kick => tr!(language, "commands.penalty_kick"),
+            Penalty::TempBan { minutes } => {
+                tr!(language, "commands.penalty_tempban", minutes = minutes)
+            }
+            Penalty::Mute => tr!(language, "commands.penalty_mute"),
+        }
+    }
+
+    // Whether the user ends up out of the group
+    pub fn removes_member(self) -> bool {
+        self != Penalty::Mute
+    }
+
+    // Past participle for the Matrix notifications: "fue {}"
+    pub fn log_description(self) -> String {
+        match self {
+            Penalty::Ban => "baneado".to_string(),
+            Penalty::Kick => "expulsado".to_string(),
+            Penalty::TempBan { minutes } => format!("baneado durante {} minutos", minutes),
+            Penalty::Mute => "silenciado".to_string(),
+        }
+    }
+
+    // Muted users keep the restriction they got when the challenge started, so
+    // there is nothing to do for them; message history can only go with a ban
+    pub async fn apply(
+        self,
+        telegram_client: &Telegram,
+        chat_id: i64,
+        user_id: i64,
+        revoke_messages: bool,
+    ) -> Result<(), TelegramError> {
+        match self {
+            Penalty::Ban => {
+                telegram_client
+                    .ban_chat_member_until(chat_id, user_id, None, revoke_messages)
+                    .await
+            }
+            Penalty::Kick => {
+                telegram_client
+                    .ban_chat_member_until(chat_id, user_id, None, revoke_messages)
+                    .await?;
+                telegram_client.unban_chat_member(chat_id, user_id).await
+            }
+            Penalty::TempBan { minutes } => {
+                let until_date = unix_now() + minutes * 60;
+                telegram_client
+                    .ban_chat_member_until(chat_id, user_id, Some(until_date), revoke_messages)
+                    .await
+            }
+            Penalty::Mute => Ok(()),
+        }
+    }
+}
+
+// Whether failures add up, and where they are counted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StrikeMode {
+    #[default]
+    Off, // Every failure gets the chat's penalty
+    Chat,   // Failures in this chat climb the ladder
+    Global, // So do failures in any other chat
+}
+
+impl StrikeMode {
+    pub const ALL: &'static [StrikeMode] = &[StrikeMode::Off, StrikeMode::Chat, StrikeMode::Global];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StrikeMode::Off => "off",
+            StrikeMode::Chat => "chat",
+            StrikeMode::Global => "global",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|mode| mode.name() == name)
+    }
+}
+
+// Failures of each user by chat: user_id -> (chat_id -> failures)
+pub type Strikes = HashMap<i64, HashMap<i64, u32>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_penalty() {
+        assert_eq!(Penalty::parse(&["ban"]), Some(Penalty::Ban));
+        assert_eq!(Penalty::parse(&["kick"]), Some(Penalty::Kick));
+        assert_eq!(Penalty::parse(&["mute"]), Some(Penalty::Mute));
+        assert_eq!(
+            Penalty::parse(&["tempban", "60"]),
+            Some(Penalty::TempBan { minutes: 60 })
+        );
+        assert_eq!(Penalty::parse(&["tempban"]), None);
+        assert_eq!(Penalty::parse(&["tempban", "0"]), None);
+        assert_eq!(
+            Penalty::parse(&["tempban", &(MAX_TEMPBAN_MINUTES + 1).to_string()]),
+            None
+        );
+        assert_eq!(Penalty::parse(&["ban", "60"]), None);
+        assert_eq!(Penalty::parse(&["forever"]), None);
+    }
+
+    #[test]
+    fn test_strikes_climb_the_ladder() {
+        assert_eq!(Penalty::for_strike(1), Penalty::Kick);
+        assert_eq!(Penalty::for_strike(2), Penalty::TempBan { minutes: 1440 });
+        assert_eq!(Penalty::for_strike(3), Penalty::Ban);
+        assert_eq!(Penalty::for_strike(7), Penalty::Ban);
+    }
+}
@@ -0,0 +1,40 @@
+use tokio::time::Duration;
+use tracing::{debug, error, info};
+
+use crate::dispatcher::{AppState, Dispatcher};
+use crate::telegram::allowed_updates_from_env;
+
+// --- Polling mode: long-poll getUpdates and hand every update to the dispatcher ---
+
+pub async fn run_polling(app: AppState) {
+    let telegram_client = app.telegram_client.clone();
+
+    // getUpdates is refused while a webhook is registered, e.g. after switching modes
+    if let Err(e) = telegram_client.delete_webhook().await {
+        error!("Failed to delete webhook before polling: {}", e);
+    }
+
+    info!("👂️ Listening for updates...");
+
+    let dispatcher = Dispatcher::from_env(app);
+    let allowed_updates = allowed_updates_from_env();
+    debug!("Requesting update types: {:?}", allowed_updates);
+
+    let mut offset = 0u64;
+    loop {
+        match telegram_client.get_updates(offset, &allowed_updates).await {
+            Ok(updates) => {
+                for update in updates {
+                    offset = update.update_id + 1;
+
+                    dispatcher.dispatch(update);
+                }
+            }
+            Err(e) => {
+                error!("Error fetching updates: {}", e);
+                let delay = e.retry_after().unwrap_or(Duration::from_secs(5));
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
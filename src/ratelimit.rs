@@ -0,0 +1,172 @@
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use tokio::sync::Mutex;
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::debug;
+
+// --- Outgoing request pacing for the Telegram Bot API ---
+//
+// Telegram allows about 30 requests per second overall and roughly one message
+// per second in the same chat. A request that posts a message first reserves the
+// next slot of its chat, then every request takes the first free global slot from
+// that time on and waits for it. Free global slots before a later reservation are
+// still handed out, so a busy chat does not hold up the others.
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    global_interval: Duration,
+    chat_interval: Duration,
+    global: Mutex<GlobalSchedule>,
+    next_per_chat: Mutex<HashMap<i64, Instant>>,
+}
+
+#[derive(Debug)]
+struct GlobalSchedule {
+    not_before: Instant,      // Pushed back by a 429 on a method paced only globally
+    slots: BTreeSet<Instant>, // Handed out and not yet past
+}
+
+impl GlobalSchedule {
+    // First slot from `earliest` on that is `interval` away from every other one
+    fn reserve_at(&mut self, earliest: Instant, interval: Duration) -> Instant {
+        let now = Instant::now();
+        self.slots.retain(|slot| *slot + interval > now);
+        let mut slot = earliest.max(self.not_before).max(now);
+        let from = slot.checked_sub(interval).unwrap_or(slot);
+        for taken in self.slots.range(from..) {
+            if *taken >= slot + interval {
+                break;
+            }
+            slot = slot.max(*taken + interval);
+        }
+        self.slots.insert(slot);
+        slot
+    }
+}
+
+impl RateLimiter {
+    pub fn new(global_interval: Duration, chat_interval: Duration) -> Self {
+        RateLimiter {
+            global_interval,
+            chat_interval,
+            global: Mutex::new(GlobalSchedule {
+                not_before: Instant::now(),
+                slots: BTreeSet::new(),
+            }),
+            next_per_chat: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let requests_per_second = env::var("TELEGRAM_GLOBAL_RATE_LIMIT")
+            .unwrap_or_else(|_| "30".to_string())
+            .parse::<u64>()
+            .unwrap_or(30)
+            .max(1);
+        let chat_interval_ms = env::var("TELEGRAM_CHAT_INTERVAL_MS")
+            .unwrap_or_else(|_| "1000".to_string())
+            .parse::<u64>()
+            .unwrap_or(1000);
+        Self::new(
+            Duration::from_millis(1000 / requests_per_second),
+            Duration::from_millis(chat_interval_ms),
+        )
+    }
+
+    // Waits for the next global slot and, if `chat_id` is given, for the chat's slot too
+    pub async fn acquire(&self, chat_id: Option<i64>) {
+        let slot = self.reserve_slot(chat_id).await;
+        if slot > Instant::now() {
+            debug!("Rate limited, waiting {:?}", slot - Instant::now());
+            sleep_until(slot).await;
+        }
+    }
+
+    // The global slot is taken no earlier than the chat's, so messages that
+    // different chats release at the same time are still spaced
+    async fn reserve_slot(&self, chat_id: Option<i64>) -> Instant {
+        let mut earliest = Instant::now();
+        if let Some(chat_id) = chat_id {
+            let mut per_chat = self.next_per_chat.lock().await;
+            per_chat.retain(|_, next| *next > earliest);
+            let next = per_chat.entry(chat_id).or_insert(earliest);
+            earliest = Self::reserve(next, self.chat_interval);
+        }
+        self.global
+            .lock()
+            .await
+            .reserve_at(earliest, self.global_interval)
+    }
+
+    // Pushes the schedule back after a 429, so the other pending requests wait as well
+    pub async fn back_off(&self, chat_id: Option<i64>, retry_after: Duration) {
+        let until = Instant::now() + retry_after;
+        match chat_id {
+            Some(chat_id) => {
+                let mut per_chat = self.next_per_chat.lock().await;
+                let next = per_chat.entry(chat_id).or_insert(until);
+                *next = (*next).max(until);
+            }
+            None => {
+                let mut global = self.global.lock().await;
+                global.not_before = global.not_before.max(until);
+            }
+        }
+    }
+
+    fn reserve(next: &mut Instant, interval: Duration) -> Instant {
+        let slot = (*next).max(Instant::now());
+        *next = slot + interval;
+        slot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_to_the_same_chat_are_spaced() {
+        let limiter = RateLimiter::new(Duration::from_millis(10), Duration::from_secs(1));
+        let start = Instant::now();
+
+        limiter.acquire(Some(1)).await;
+        limiter.acquire(Some(2)).await;
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        limiter.acquire(Some(1)).await;
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chats_released_together_keep_the_global_spacing() {
+        let interval = Duration::from_millis(100);
+        let limiter = RateLimiter::new(interval, Duration::from_secs(1));
+        let start = Instant::now();
+
+        limiter.reserve_slot(Some(1)).await;
+        limiter.reserve_slot(Some(2)).await;
+        // Both chats are free again after a second, but not both at once
+        let first = limiter.reserve_slot(Some(1)).await;
+        let second = limiter.reserve_slot(Some(2)).await;
+        assert_eq!(first, start + Duration::from_secs(1));
+        assert_eq!(second, first + interval);
+
+        // A third chat gets a free slot before them
+        let other = limiter.reserve_slot(Some(3)).await;
+        assert_eq!(other, start + 2 * interval);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_back_off_delays_the_chat() {
+        let limiter = RateLimiter::new(Duration::from_millis(10), Duration::ZERO);
+        let start = Instant::now();
+
+        limiter.back_off(Some(1), Duration::from_secs(5)).await;
+        limiter.acquire(Some(2)).await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        limiter.acquire(Some(1)).await;
+        assert!(start.elapsed() >= Duration::from_secs(5));
+    }
+}
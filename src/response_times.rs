@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use tokio::time::Duration;
+
+// --- How fast people answer in a chat ---
+//
+// The too-fast check starts at MIN_RESPONSE_SECONDS. Once a chat has seen enough
+// people pass, the threshold follows them: an answer well under what the quickest
+// humans of that chat manage is flagged, since it is most likely a script reading the
+// buttons. Only passed, not-too-fast answers are recorded, so flagged bots cannot
+// drag the threshold down.
+
+const MAX_SAMPLES: usize = 200; // Only the latest answers count, so the data ages out
+pub const MIN_SAMPLES: usize = 20; // Below this the fixed minimum is used
+const PERCENTILE: f64 = 0.05; // The quickest humans...
+const MARGIN: f64 = 0.5; // ...and half their time is implausible
+const MAX_THRESHOLD: Duration = Duration::from_secs(10); // Never flag a careful reader
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResponseTimes {
+    samples_ms: VecDeque<u64>,
+}
+
+impl ResponseTimes {
+    pub fn record(&mut self, response_time: Duration) {
+        if self.samples_ms.len() == MAX_SAMPLES {
+            self.samples_ms.pop_front();
+        }
+        self.samples_ms.push_back(response_time.as_millis() as u64);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples_ms.len()
+    }
+
+    // Nearest-rank percentile, `fraction` between 0 and 1
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        let mut sorted: Vec<u64> = self.samples_ms.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = ((fraction * sorted.len() as f64).ceil() as usize).max(1);
+        sorted
+            .get(rank - 1)
+            .map(|millis| Duration::from_millis(*millis))
+    }
+
+    // The fixed minimum until there is enough data, then the adaptive threshold,
+    // which never goes below the minimum nor above MAX_THRESHOLD
+    pub fn threshold(&self, fixed: Duration) -> Duration {
+        if self.len() < MIN_SAMPLES {
+            return fixed;
+        }
+        self.percentile(PERCENTILE)
+            .map_or(fixed, |quickest| quickest.mul_f64(MARGIN))
+            .min(MAX_THRESHOLD)
+            .max(fixed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn times(seconds: &[u64]) -> ResponseTimes {
+        let mut times = ResponseTimes::default();
+        for seconds in seconds {
+            times.record(Duration::from_secs(*seconds));
+        }
+        times
+    }
+
+    #[test]
+    fn test_threshold_falls_back_without_enough_data() {
+        let fixed = Duration::from_secs(1);
+        assert_eq!(times(&[8; MIN_SAMPLES - 1]).threshold(fixed), fixed);
+        assert_eq!(
+            times(&[8; MIN_SAMPLES]).threshold(fixed),
+            Duration::from_secs(4)
+        );
+    }
+
+    #[test]
+    fn test_threshold_follows_the_quickest_humans() {
+        let fixed = Duration::from_secs(1);
+        // 5% of the answers take 6 seconds, the rest 20
+        let mut seconds = vec![20; 95];
+        seconds.extend([6; 5]);
+        assert_eq!(times(&seconds).threshold(fixed), Duration::from_secs(3));
+        // Bounded on both sides
+        assert_eq!(times(&[1; 40]).threshold(fixed), fixed);
+        assert_eq!(times(&[60; 40]).threshold(fixed), MAX_THRESHOLD);
+    }
+
+    #[test]
+    fn test_only_the_latest_answers_are_kept() {
+        let mut response_times = times(&[2; MAX_SAMPLES]);
+        for _ in 0..MAX_SAMPLES {
+            response_times.record(Duration::from_secs(30));
+        }
+        assert_eq!(response_times.len(), MAX_SAMPLES);
+        assert_eq!(
+            response_times.percentile(0.0),
+            Some(Duration::from_secs(30))
+        );
+    }
+}
@@ -0,0 +1,149 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::env;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info};
+
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+// --- On-disk JSON storage under DATA_DIR ---
+
+#[derive(Debug, Clone)]
+pub struct Storage {
+    data_dir: PathBuf,
+}
+
+impl Storage {
+    pub fn new(data_dir: impl Into<PathBuf>) -> Self {
+        Storage {
+            data_dir: data_dir.into(),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let data_dir = env::var("DATA_DIR").unwrap_or_else(|_| DEFAULT_DATA_DIR.to_string());
+        Self::new(data_dir)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.data_dir.join(format!("{}.json", name))
+    }
+
+    /// Loads `<DATA_DIR>/<name>.json`, falling back to `T::default()` when the
+    /// file does not exist yet or cannot be parsed. An unparsable file is moved
+    /// aside first, so the next save does not overwrite what it held.
+    pub fn load<T: DeserializeOwned + Default>(&self, name: &str) -> T {
+        let path = self.path(name);
+        match std::fs::read_to_string(&path) {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(value) => {
+                    info!("Loaded {} from {}", name, path.display());
+                    value
+                }
+                Err(e) => {
+                    error!("Failed to parse {}: {}", path.display(), e);
+                    self.set_aside(name);
+                    T::default()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("{} does not exist yet, starting empty", path.display());
+                T::default()
+            }
+            Err(e) => {
+                error!("Failed to read {}: {}. Starting empty", path.display(), e);
+                T::default()
+            }
+        }
+    }
+
+    // Renames the file to `<name>.json.corrupt-<unix time>` for a human to look at
+    fn set_aside(&self, name: &str) {
+        let path = self.path(name);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let corrupt_path = self
+            .data_dir
+            .join(format!("{}.json.corrupt-{}", name, timestamp));
+        match std::fs::rename(&path, &corrupt_path) {
+            Ok(()) => error!(
+                "Moved {} to {}. Starting empty",
+                path.display(),
+                corrupt_path.display()
+            ),
+            Err(e) => error!(
+                "Failed to move {} aside: {}. Starting empty",
+                path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Writes `value` to `<DATA_DIR>/<name>.json` through a temporary file so a
+    /// crash mid-write never leaves a truncated file behind.
+    pub async fn save<T: Serialize>(
+        &self,
+        name: &str,
+        value: &T,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let content = serde_json::to_string_pretty(value)?;
+        tokio::fs::create_dir_all(&self.data_dir).await?;
+        let path = self.path(name);
+        let tmp_path = self.data_dir.join(format!("{}.json.tmp", name));
+        tokio::fs::write(&tmp_path, content).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        debug!("Saved {} to {}", name, path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn test_save_and_load_roundtrip() {
+        let dir = env::temp_dir().join(format!("expulsabot-{}", uuid::Uuid::new_v4()));
+        let storage = Storage::new(&dir);
+
+        let empty: HashMap<i64, u64> = storage.load("counters");
+        assert!(empty.is_empty());
+
+        let mut counters = HashMap::new();
+        counters.insert(-100123_i64, 7_u64);
+        storage.save("counters", &counters).await.unwrap();
+
+        let loaded: HashMap<i64, u64> = storage.load("counters");
+        assert_eq!(loaded, counters);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_unparsable_file_is_set_aside() {
+        let dir = env::temp_dir().join(format!("expulsabot-{}", uuid::Uuid::new_v4()));
+        let storage = Storage::new(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("counters.json"), "{ not json").unwrap();
+
+        let loaded: HashMap<i64, u64> = storage.load("counters");
+        assert!(loaded.is_empty());
+        storage.save("counters", &loaded).await.unwrap();
+
+        // The broken content survives the save that follows
+        let corrupt: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("counters.json.corrupt-"))
+            .collect();
+        assert_eq!(corrupt.len(), 1);
+        let content = std::fs::read_to_string(dir.join(&corrupt[0])).unwrap();
+        assert_eq!(content, "{ not json");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,12 +1,38 @@
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::time::Duration;
+use tracing::{debug, error, warn};
+
+use crate::ratelimit::RateLimiter;
+
+pub const DEFAULT_TELEGRAM_API_URL: &str = "https://api.telegram.org";
+
+// Base URL of the Bot API, e.g. a self-hosted telegram-bot-api server or a local mock
+pub fn api_url_from_env() -> String {
+    std::env::var("TELEGRAM_API_URL")
+        .ok()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| DEFAULT_TELEGRAM_API_URL.to_string())
+}
 
-pub const TELEGRAM_API_URL: &str = "https://api.telegram.org/bot";
+// Update types requested from Telegram unless ALLOWED_UPDATES says otherwise.
+// chat_member is not delivered by default, so it has to be listed explicitly.
+pub const DEFAULT_ALLOWED_UPDATES: &[&str] = &[
+    "message",
+    "callback_query",
+    "chat_member",
+    "my_chat_member",
+    "chat_join_request",
+];
 
 // --- Structs for Telegram API responses ---
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct InlineKeyboardButton {
     pub text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -15,7 +41,7 @@ pub struct InlineKeyboardButton {
     pub callback_data: Option<String>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct InlineKeyboardMarkup {
     pub inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
 }
@@ -48,15 +74,128 @@ pub struct Chat {
     pub is_forum: Option<bool>,
 }
 
+// What a member may do. Sent with use_independent_chat_permissions, so every field
+// counts on its own and the legacy can_send_media_messages is not needed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChatPermissions {
+    pub can_send_messages: bool,
+    pub can_send_audios: bool,
+    pub can_send_documents: bool,
+    pub can_send_photos: bool,
+    pub can_send_videos: bool,
+    pub can_send_video_notes: bool,
+    pub can_send_voice_notes: bool,
+    pub can_send_polls: bool,
+    pub can_send_other_messages: bool, // Stickers, GIFs, games and inline bots
+    pub can_add_web_page_previews: bool,
+    pub can_change_info: bool,
+    pub can_invite_users: bool,
+    pub can_pin_messages: bool,
+    pub can_manage_topics: bool,
+}
+
+impl ChatPermissions {
+    // Used while a challenge is pending
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    // Fallback when the chat's own permissions cannot be read: send anything,
+    // but leave the chat info, pins, invites and topics to the admins
+    pub fn member_defaults() -> Self {
+        ChatPermissions {
+            can_send_messages: true,
+            can_send_audios: true,
+            can_send_documents: true,
+            can_send_photos: true,
+            can_send_videos: true,
+            can_send_video_notes: true,
+            can_send_voice_notes: true,
+            can_send_polls: true,
+            can_send_other_messages: true,
+            can_add_web_page_previews: true,
+            ..Self::default()
+        }
+    }
+
+    // Short names used by the /permissions command
+    fn named_fields(&mut self) -> [(&'static str, &mut bool); 14] {
+        [
+            ("messages", &mut self.can_send_messages),
+            ("audios", &mut self.can_send_audios),
+            ("documents", &mut self.can_send_documents),
+            ("photos", &mut self.can_send_photos),
+            ("videos", &mut self.can_send_videos),
+            ("video_notes", &mut self.can_send_video_notes),
+            ("voice_notes", &mut self.can_send_voice_notes),
+            ("polls", &mut self.can_send_polls),
+            ("other", &mut self.can_send_other_messages),
+            ("previews", &mut self.can_add_web_page_previews),
+            ("info", &mut self.can_change_info),
+            ("invite", &mut self.can_invite_users),
+            ("pin", &mut self.can_pin_messages),
+            ("topics", &mut self.can_manage_topics),
+        ]
+    }
+
+    pub fn field_names() -> Vec<&'static str> {
+        Self::default()
+            .named_fields()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    // Builds the permissions granting exactly the named ones; returns the unknown name on error
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<Self, &'a str> {
+        let mut permissions = Self::default();
+        for name in names {
+            let mut fields = permissions.named_fields();
+            let (_, granted) = fields
+                .iter_mut()
+                .find(|(field, _)| *field == name)
+                .ok_or(name)?;
+            **granted = true;
+        }
+        Ok(permissions)
+    }
+
+    pub fn granted_names(&self) -> Vec<&'static str> {
+        self.clone()
+            .named_fields()
+            .into_iter()
+            .filter(|(_, granted)| **granted)
+            .map(|(name, _)| name)
+            .collect()
+    }
+}
+
+// Subset of the getChat result we use
+#[derive(Debug, Deserialize)]
+pub struct ChatFullInfo {
+    pub id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<ChatPermissions>, // Default member permissions, groups only
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Member {
     pub status: String, // e.g., "member", "administrator", "restricted", "left", "kicked"
     pub user: User,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub can_restrict_members: Option<bool>, // Only for administrators
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub can_delete_messages: Option<bool>, // Only for administrators
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Message {
     pub message_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_thread_id: Option<i64>, // Forum topic (or reply thread) of the message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_topic_message: Option<bool>, // True when sent inside a forum topic
     pub chat: Chat,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub text: Option<String>,
@@ -69,6 +208,18 @@ pub struct Message {
     pub new_chat_member: Option<User>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub new_chat_participant: Option<User>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub migrate_to_chat_id: Option<i64>, // Group upgraded to this supergroup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub migrate_from_chat_id: Option<i64>, // Supergroup upgraded from this group
+}
+
+impl Message {
+    // Forum topic the message was posted in; reply threads of ordinary groups don't count
+    pub fn topic_id(&self) -> Option<i64> {
+        self.message_thread_id
+            .filter(|_| self.is_topic_message == Some(true))
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -80,6 +231,16 @@ pub struct ChatMemberUpdated {
     pub new_chat_member: Member,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ChatJoinRequest {
+    pub chat: Chat,
+    pub from: User,
+    pub user_chat_id: i64, // Private chat with the user, usable for 5 minutes
+    pub date: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CallbackQuery {
     pub id: String,
@@ -117,23 +278,145 @@ pub struct Update {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub poll_answer: Option<serde_json::Value>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub my_chat_member: Option<serde_json::Value>,
+    pub my_chat_member: Option<ChatMemberUpdated>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub chat_join_request: Option<serde_json::Value>,
+    pub chat_join_request: Option<ChatJoinRequest>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct SentMessageResult {
     pub message_id: u64,
-    pub chat: Chat,
-    pub text: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResponseParameters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub migrate_to_chat_id: Option<i64>, // The group was upgraded to this supergroup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>, // Seconds to wait after a 429 flood error
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TelegramResponse<T> {
     pub ok: bool,
-    pub result: T,
+    pub result: Option<T>,
     pub description: Option<String>,
+    pub error_code: Option<i64>,
+    pub parameters: Option<ResponseParameters>,
+}
+
+// --- Errors ---
+
+#[derive(Debug)]
+pub enum TelegramError {
+    // Network failure or unreadable body
+    Network(reqwest::Error),
+    // Non-JSON answer, e.g. from a proxy in front of the Bot API
+    Http {
+        status: u16,
+        body: String,
+    },
+    // Payload or response (de)serialization
+    Json(serde_json::Error),
+    // Telegram answered ok: false
+    Api {
+        method: String,
+        error_code: Option<i64>,
+        description: String,
+        parameters: Option<ResponseParameters>,
+    },
+}
+
+impl TelegramError {
+    pub fn error_code(&self) -> Option<i64> {
+        match self {
+            TelegramError::Api { error_code, .. } => *error_code,
+            TelegramError::Http { status, .. } => Some(i64::from(*status)),
+            _ => None,
+        }
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            TelegramError::Api {
+                parameters: Some(parameters),
+                ..
+            } => parameters.retry_after.map(Duration::from_secs),
+            _ => None,
+        }
+    }
+
+    pub fn migrate_to_chat_id(&self) -> Option<i64> {
+        match self {
+            TelegramError::Api {
+                parameters: Some(parameters),
+                ..
+            } => parameters.migrate_to_chat_id,
+            _ => None,
+        }
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.error_code() == Some(429)
+    }
+}
+
+impl fmt::Display for TelegramError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TelegramError::Network(e) => write!(f, "Request to Telegram failed: {}", e),
+            TelegramError::Http { status, body } => {
+                write!(f, "HTTP Error: {}. {}", status, body)
+            }
+            TelegramError::Json(e) => write!(f, "Invalid Telegram JSON: {}", e),
+            TelegramError::Api {
+                method,
+                error_code,
+                description,
+                ..
+            } => write!(
+                f,
+                "Telegram API Error ({}/{}): {}",
+                method,
+                error_code.unwrap_or_default(),
+                description
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TelegramError {}
+
+impl From<reqwest::Error> for TelegramError {
+    fn from(e: reqwest::Error) -> Self {
+        TelegramError::Network(e)
+    }
+}
+
+impl From<serde_json::Error> for TelegramError {
+    fn from(e: serde_json::Error) -> Self {
+        TelegramError::Json(e)
+    }
+}
+
+// Methods that post to a chat or edit what is there, and therefore count against
+// its per-chat limit
+const MESSAGE_METHODS: &[&str] = &[
+    "sendMessage",
+    "sendPhoto",
+    "editMessageText",
+    "editMessageCaption",
+    "editMessageMedia",
+    "editMessageReplyMarkup",
+];
+
+// A file uploaded as a multipart field next to the rest of the payload
+#[derive(Debug, Clone)]
+pub struct InputFile {
+    pub field: &'static str,
+    pub file_name: String,
+    pub mime_type: &'static str,
+    pub bytes: Vec<u8>,
 }
 
 // --- Helper functions for Telegram API interactions ---
@@ -142,54 +425,229 @@ pub struct TelegramResponse<T> {
 pub struct Telegram {
     client: Client,
     token: String,
+    api_url: String,
+    local_server: bool, // Talking to a self-hosted telegram-bot-api rather than api.telegram.org
+    rate_limiter: Arc<RateLimiter>,
+    max_retries: u32,
+    chat_migrations: Arc<std::sync::Mutex<HashMap<i64, i64>>>, // Old group -> supergroup
+    learned_migrations: broadcast::Sender<(i64, i64)>,         // The ones found out from API errors
+    bot_username: Arc<std::sync::OnceLock<String>>,            // From getMe, for t.me links
 }
 
 impl Telegram {
-    pub fn new(token: &str) -> Self {
+    pub fn new(token: &str, api_url: &str) -> Self {
+        let api_url = api_url.trim_end_matches('/').to_string();
+        // The local server lifts the cloud limits (uploads up to 2000 MB, plain
+        // HTTP webhooks on any port); TELEGRAM_LOCAL_SERVER overrides the guess
+        let local_server = std::env::var("TELEGRAM_LOCAL_SERVER")
+            .ok()
+            .and_then(|value| value.parse::<bool>().ok())
+            .unwrap_or(api_url != DEFAULT_TELEGRAM_API_URL);
+        let max_retries = std::env::var("TELEGRAM_MAX_RETRIES")
+            .unwrap_or_else(|_| "3".to_string())
+            .parse::<u32>()
+            .unwrap_or(3);
         Telegram {
             client: Client::new(),
             token: token.to_string(),
+            api_url,
+            local_server,
+            rate_limiter: Arc::new(RateLimiter::from_env()),
+            max_retries,
+            chat_migrations: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            learned_migrations: broadcast::channel(16).0,
+            bot_username: Arc::new(std::sync::OnceLock::new()),
         }
     }
 
+    #[cfg(test)]
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Arc::new(rate_limiter);
+        self
+    }
+
+    pub fn is_local_server(&self) -> bool {
+        self.local_server
+    }
+
+    fn method_url(&self, method: &str) -> String {
+        format!("{}/bot{}/{}", self.api_url, self.token, method)
+    }
+
+    pub fn remember_chat_migration(&self, from_chat_id: i64, to_chat_id: i64) {
+        if let Ok(mut migrations) = self.chat_migrations.lock() {
+            migrations.insert(from_chat_id, to_chat_id);
+        }
+    }
+
+    // Migrations a request ran into, for groups whose upgrade service message was
+    // never seen: the bot's state for them still sits under the old id
+    pub fn subscribe_chat_migrations(&self) -> broadcast::Receiver<(i64, i64)> {
+        self.learned_migrations.subscribe()
+    }
+
+    // Current id of a chat that may have been upgraded to a supergroup since
+    pub fn resolve_chat_id(&self, chat_id: i64) -> i64 {
+        self.chat_migrations
+            .lock()
+            .ok()
+            .and_then(|migrations| migrations.get(&chat_id).copied())
+            .unwrap_or(chat_id)
+    }
+
+    // Paces every call through the rate limiter, retries 429s after `retry_after`
+    // and follows groups that were upgraded to a supergroup
     pub async fn send_request<T: for<'de> Deserialize<'de> + 'static>(
         &self,
         method: &str,
         json_payload: serde_json::Value,
-    ) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
-        let url = format!("{}{}/{}", TELEGRAM_API_URL, &self.token, method);
+    ) -> Result<T, TelegramError> {
+        self.send_request_with_file(method, json_payload, None)
+            .await
+    }
+
+    // Same as send_request, posting multipart/form-data when there is a file to upload
+    pub async fn send_request_with_file<T: for<'de> Deserialize<'de> + 'static>(
+        &self,
+        method: &str,
+        mut json_payload: serde_json::Value,
+        file: Option<&InputFile>,
+    ) -> Result<T, TelegramError> {
+        let mut chat_id = json_payload.get("chat_id").and_then(|id| id.as_i64());
+        if let Some(old_chat_id) = chat_id {
+            let new_chat_id = self.resolve_chat_id(old_chat_id);
+            if new_chat_id != old_chat_id {
+                json_payload["chat_id"] = serde_json::json!(new_chat_id);
+                chat_id = Some(new_chat_id);
+            }
+        }
+
+        let mut attempt = 0;
+        loop {
+            let paced_chat_id = chat_id.filter(|_| MESSAGE_METHODS.contains(&method));
+            self.rate_limiter.acquire(paced_chat_id).await;
+            match self.send_request_once(method, &json_payload, file).await {
+                Err(e) if e.migrate_to_chat_id().is_some() && attempt < self.max_retries => {
+                    let (Some(old_chat_id), Some(new_chat_id)) = (chat_id, e.migrate_to_chat_id())
+                    else {
+                        return Err(e);
+                    };
+                    attempt += 1;
+                    warn!(
+                        "Chat {} was upgraded to supergroup {}, retrying {}",
+                        old_chat_id, new_chat_id, method
+                    );
+                    self.remember_chat_migration(old_chat_id, new_chat_id);
+                    let _ = self.learned_migrations.send((old_chat_id, new_chat_id));
+                    json_payload["chat_id"] = serde_json::json!(new_chat_id);
+                    chat_id = Some(new_chat_id);
+                }
+                Err(e) if e.is_rate_limited() && attempt < self.max_retries => {
+                    let retry_after = e.retry_after().unwrap_or(Duration::from_secs(1));
+                    attempt += 1;
+                    warn!(
+                        "Rate limited on {} (chat {:?}), retrying in {:?} ({}/{})",
+                        method, chat_id, retry_after, attempt, self.max_retries
+                    );
+                    // The slot the retry will wait for: its chat's for messages,
+                    // the global one for everything else
+                    self.rate_limiter.back_off(paced_chat_id, retry_after).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send_request_once<T: for<'de> Deserialize<'de> + 'static>(
+        &self,
+        method: &str,
+        json_payload: &serde_json::Value,
+        file: Option<&InputFile>,
+    ) -> Result<T, TelegramError> {
+        let url = self.method_url(method);
         debug!(
             "Sending request to Telegram API: {} with payload: {}",
             url, json_payload
         );
-        let response = self.client.post(&url).json(&json_payload).send().await?;
+        let request = self.client.post(&url);
+        let request = match file {
+            Some(file) => request.multipart(Self::multipart_form(json_payload, file)?),
+            None => request.json(json_payload),
+        };
+        let response = request.send().await?;
+        let status = response.status();
+        let response_text = response.text().await?;
+        Self::parse_response(method, status.as_u16(), &response_text)
+    }
 
-        let response_json = response.json::<TelegramResponse<T>>().await?;
+    // Multipart fields are plain strings: nested values such as reply_markup go as JSON
+    fn multipart_form(
+        json_payload: &serde_json::Value,
+        file: &InputFile,
+    ) -> Result<reqwest::multipart::Form, TelegramError> {
+        let mut form = reqwest::multipart::Form::new();
+        if let Some(fields) = json_payload.as_object() {
+            for (name, value) in fields {
+                let value = match value {
+                    serde_json::Value::String(text) => text.clone(),
+                    value => value.to_string(),
+                };
+                form = form.text(name.clone(), value);
+            }
+        }
+        let part = reqwest::multipart::Part::bytes(file.bytes.clone())
+            .file_name(file.file_name.clone())
+            .mime_str(file.mime_type)?;
+        Ok(form.part(file.field, part))
+    }
 
-        if response_json.ok {
-            Ok(response_json.result)
-        } else {
-            let error_message = response_json
-                .description
-                .unwrap_or_else(|| "Unknown error".to_string());
-            Err(format!(
-                "Telegram API Error ({}/{}): {}",
-                method, response_json.ok, error_message
-            )
-            .into())
+    fn parse_response<T: for<'de> Deserialize<'de>>(
+        method: &str,
+        status: u16,
+        response_text: &str,
+    ) -> Result<T, TelegramError> {
+        let response_json = match serde_json::from_str::<TelegramResponse<T>>(response_text) {
+            Ok(response_json) => response_json,
+            Err(_) if !(200..300).contains(&status) => {
+                return Err(TelegramError::Http {
+                    status,
+                    body: response_text.to_string(),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match response_json {
+            TelegramResponse {
+                ok: true,
+                result: Some(result),
+                ..
+            } => Ok(result),
+            response_json => Err(TelegramError::Api {
+                method: method.to_string(),
+                error_code: response_json.error_code,
+                description: response_json
+                    .description
+                    .unwrap_or_else(|| "Unknown error".to_string()),
+                parameters: response_json.parameters,
+            }),
         }
     }
 
     pub async fn send_message(
         &self,
         chat_id: i64,
+        message_thread_id: Option<i64>,
         text: &str,
-    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
-        let payload = serde_json::json!({
+    ) -> Result<u64, TelegramError> {
+        let mut payload = serde_json::json!({
             "chat_id": chat_id,
             "text": text,
             "parse_mode": "HTML",
         });
+        if let Some(message_thread_id) = message_thread_id {
+            payload["message_thread_id"] = serde_json::json!(message_thread_id);
+        }
         self.send_request("sendMessage", payload)
             .await
             .map(|result: SentMessageResult| result.message_id)
@@ -198,87 +656,231 @@ impl Telegram {
     pub async fn send_message_with_keyboard(
         &self,
         chat_id: i64,
+        message_thread_id: Option<i64>,
         text: &str,
         keyboard: InlineKeyboardMarkup,
-    ) -> Result<u64, Box<dyn std::error::Error + Send + Sync>> {
-        let payload = serde_json::json!({
+    ) -> Result<u64, TelegramError> {
+        let mut payload = serde_json::json!({
             "chat_id": chat_id,
             "text": text,
             "parse_mode": "HTML",
             "reply_markup": serde_json::to_value(keyboard)?,
         });
+        if let Some(message_thread_id) = message_thread_id {
+            payload["message_thread_id"] = serde_json::json!(message_thread_id);
+        }
         self.send_request("sendMessage", payload)
             .await
             .map(|result: SentMessageResult| result.message_id)
     }
 
+    // The caption carries the text, so it is limited to 1024 characters
+    pub async fn send_photo(
+        &self,
+        chat_id: i64,
+        message_thread_id: Option<i64>,
+        photo: InputFile,
+        caption: &str,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<u64, TelegramError> {
+        let mut payload = serde_json::json!({
+            "chat_id": chat_id,
+            "caption": caption,
+            "parse_mode": "HTML",
+            "reply_markup": serde_json::to_value(keyboard)?,
+        });
+        if let Some(message_thread_id) = message_thread_id {
+            payload["message_thread_id"] = serde_json::json!(message_thread_id);
+        }
+        self.send_request_with_file("sendPhoto", payload, Some(&photo))
+            .await
+            .map(|result: SentMessageResult| result.message_id)
+    }
+
+    pub async fn edit_message_text(
+        &self,
+        chat_id: i64,
+        message_id: u64,
+        text: &str,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), TelegramError> {
+        let payload = serde_json::json!({
+            "chat_id": chat_id,
+            "message_id": message_id,
+            "text": text,
+            "parse_mode": "HTML",
+            "reply_markup": serde_json::to_value(keyboard)?,
+        });
+        let _: serde_json::Value = self.send_request("editMessageText", payload).await?;
+        Ok(())
+    }
+
+    // Text of a photo message lives in its caption
+    pub async fn edit_message_caption(
+        &self,
+        chat_id: i64,
+        message_id: u64,
+        caption: &str,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), TelegramError> {
+        let payload = serde_json::json!({
+            "chat_id": chat_id,
+            "message_id": message_id,
+            "caption": caption,
+            "parse_mode": "HTML",
+            "reply_markup": serde_json::to_value(keyboard)?,
+        });
+        let _: serde_json::Value = self.send_request("editMessageCaption", payload).await?;
+        Ok(())
+    }
+
+    // An empty keyboard removes the buttons and leaves the message as it is
+    pub async fn edit_message_reply_markup(
+        &self,
+        chat_id: i64,
+        message_id: u64,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), TelegramError> {
+        let payload = serde_json::json!({
+            "chat_id": chat_id,
+            "message_id": message_id,
+            "reply_markup": serde_json::to_value(keyboard)?,
+        });
+        let _: serde_json::Value = self.send_request("editMessageReplyMarkup", payload).await?;
+        Ok(())
+    }
+
+    // Replaces the picture of a photo message, uploading the new one
+    pub async fn edit_message_photo(
+        &self,
+        chat_id: i64,
+        message_id: u64,
+        photo: InputFile,
+        caption: &str,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), TelegramError> {
+        let payload = serde_json::json!({
+            "chat_id": chat_id,
+            "message_id": message_id,
+            "media": {
+                "type": "photo",
+                "media": format!("attach://{}", photo.field),
+                "caption": caption,
+                "parse_mode": "HTML",
+            },
+            "reply_markup": serde_json::to_value(keyboard)?,
+        });
+        let _: serde_json::Value = self
+            .send_request_with_file("editMessageMedia", payload, Some(&photo))
+            .await?;
+        Ok(())
+    }
+
+    // Granting exactly the chat's default permissions lifts the restriction
     pub async fn restrict_chat_member(
         &self,
         chat_id: i64,
         user_id: i64,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        permissions: &ChatPermissions,
+    ) -> Result<(), TelegramError> {
         let payload = serde_json::json!({
             "chat_id": chat_id,
             "user_id": user_id,
-            "permissions": {
-                "can_send_messages": false,
-                "can_send_media_messages": false,
-                "can_send_other_messages": false,
-                "can_add_web_page_previews": false,
-                "can_change_info": false,
-                "can_invite_users": false,
-                "can_pin_messages": false,
-            },
-            "use_independent_chat_permissions": false,
+            "permissions": serde_json::to_value(permissions)?,
+            "use_independent_chat_permissions": true,
             "until_date": 0
         });
         let _: bool = self.send_request("restrictChatMember", payload).await?;
         Ok(())
     }
 
-    pub async fn unrestrict_chat_member(
+    pub async fn get_chat(&self, chat_id: i64) -> Result<ChatFullInfo, TelegramError> {
+        let payload = serde_json::json!({
+            "chat_id": chat_id,
+        });
+        self.send_request("getChat", payload).await
+    }
+
+    pub async fn ban_chat_member(&self, chat_id: i64, user_id: i64) -> Result<(), TelegramError> {
+        self.ban_chat_member_until(chat_id, user_id, None, true)
+            .await
+    }
+
+    // Without until_date the ban is permanent
+    pub async fn ban_chat_member_until(
         &self,
         chat_id: i64,
         user_id: i64,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        until_date: Option<u64>,
+        revoke_messages: bool,
+    ) -> Result<(), TelegramError> {
+        let mut payload = serde_json::json!({
+            "chat_id": chat_id,
+            "user_id": user_id,
+            "revoke_messages": revoke_messages
+        });
+        if let Some(until_date) = until_date {
+            payload["until_date"] = serde_json::json!(until_date);
+        }
+        let _: bool = self.send_request("banChatMember", payload).await?;
+        Ok(())
+    }
+
+    // only_if_banned keeps Telegram from removing a user who is still a member
+    pub async fn unban_chat_member(&self, chat_id: i64, user_id: i64) -> Result<(), TelegramError> {
         let payload = serde_json::json!({
             "chat_id": chat_id,
             "user_id": user_id,
-            "permissions": {
-                "can_send_messages": true,
-                "can_send_media_messages": true,
-                "can_send_other_messages": true,
-                "can_add_web_page_previews": true,
-                "can_change_info": true,
-                "can_invite_users": true,
-                "can_pin_messages": true,
-            },
-            "use_independent_chat_permissions": false,
-            "until_date": 0
+            "only_if_banned": true
         });
-        let _: bool = self.send_request("restrictChatMember", payload).await?;
+        let _: bool = self.send_request("unbanChatMember", payload).await?;
         Ok(())
     }
 
-    pub async fn ban_chat_member(
+    pub async fn approve_chat_join_request(
         &self,
         chat_id: i64,
         user_id: i64,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    ) -> Result<(), TelegramError> {
         let payload = serde_json::json!({
             "chat_id": chat_id,
             "user_id": user_id,
-            "revoke_messages": true
         });
-        let _: bool = self.send_request("banChatMember", payload).await?;
+        let _: bool = self.send_request("approveChatJoinRequest", payload).await?;
         Ok(())
     }
 
-    pub async fn delete_message(
+    pub async fn decline_chat_join_request(
         &self,
         chat_id: i64,
-        message_id: u64,
-    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        user_id: i64,
+    ) -> Result<(), TelegramError> {
+        let payload = serde_json::json!({
+            "chat_id": chat_id,
+            "user_id": user_id,
+        });
+        let _: bool = self.send_request("declineChatJoinRequest", payload).await?;
+        Ok(())
+    }
+
+    pub async fn answer_callback_query(
+        &self,
+        callback_query_id: &str,
+        text: Option<&str>,
+        show_alert: bool,
+    ) -> Result<(), TelegramError> {
+        let mut payload = serde_json::json!({
+            "callback_query_id": callback_query_id,
+            "show_alert": show_alert,
+        });
+        if let Some(text) = text {
+            payload["text"] = serde_json::json!(text);
+        }
+        let _: bool = self.send_request("answerCallbackQuery", payload).await?;
+        Ok(())
+    }
+
+    pub async fn delete_message(&self, chat_id: i64, message_id: u64) -> Result<(), TelegramError> {
         let payload = serde_json::json!({
             "chat_id": chat_id,
             "message_id": message_id,
@@ -290,10 +892,13 @@ impl Telegram {
     pub async fn get_updates(
         &self,
         offset: u64,
-    ) -> Result<Vec<Update>, Box<dyn std::error::Error + Send + Sync>> {
+        allowed_updates: &[String],
+    ) -> Result<Vec<Update>, TelegramError> {
         let url = format!(
-            "{}{}/getUpdates?offset={}&timeout=60",
-            TELEGRAM_API_URL, &self.token, offset
+            "{}?offset={}&timeout=60&allowed_updates={}",
+            self.method_url("getUpdates"),
+            offset,
+            urlencoding::encode(&serde_json::to_string(allowed_updates)?)
         );
 
         // Debug: log the URL being called
@@ -302,45 +907,55 @@ impl Telegram {
         let response = self.client.get(&url).send().await?;
         debug!("Received response from Telegram API: {:?}", response);
         let status = response.status();
-        // Check if response status is OK
-        if !status.is_success() {
-            let content = response
-                .text()
-                .await
-                .unwrap_or_else(|_| "No content".to_string());
-            return Err(format!("HTTP Error: {}. {}", status, content).into());
-        }
 
         // Get response text for debugging
         let response_text = response.text().await?;
         debug!("Response text: {}", response_text);
 
-        // Try to parse the response
-        match serde_json::from_str::<TelegramResponse<Vec<Update>>>(&response_text) {
-            Ok(updates_response) => {
-                if updates_response.ok {
-                    Ok(updates_response.result)
-                } else {
-                    Err(updates_response
-                        .description
-                        .unwrap_or_else(|| "Unknown error".to_string())
-                        .into())
-                }
-            }
-            Err(parse_error) => {
-                // Log the response text that failed to parse
-                error!("Failed to parse response: {}", parse_error);
-                error!("Response text: {}", response_text);
-                Err(format!("Failed to parse Telegram API response: {}", parse_error).into())
-            }
+        let result = Self::parse_response("getUpdates", status.as_u16(), &response_text);
+        if let Err(TelegramError::Json(parse_error)) = &result {
+            // Log the response text that failed to parse
+            error!("Failed to parse response: {}", parse_error);
+            error!("Response text: {}", response_text);
         }
+        result
     }
 
-    pub async fn is_chat_admin(
+    pub async fn set_webhook(
         &self,
-        chat_id: i64,
-        user_id: i64,
-    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        url: &str,
+        secret_token: &str,
+        allowed_updates: &[String],
+    ) -> Result<(), TelegramError> {
+        let payload = serde_json::json!({
+            "url": url,
+            "secret_token": secret_token,
+            "allowed_updates": allowed_updates,
+        });
+        let _: bool = self.send_request("setWebhook", payload).await?;
+        Ok(())
+    }
+
+    pub async fn delete_webhook(&self) -> Result<(), TelegramError> {
+        let payload = serde_json::json!({});
+        let _: bool = self.send_request("deleteWebhook", payload).await?;
+        Ok(())
+    }
+
+    pub async fn get_me(&self) -> Result<User, TelegramError> {
+        self.send_request("getMe", serde_json::json!({})).await
+    }
+
+    // Asked once and then remembered; a bot's username cannot change while it runs
+    pub async fn bot_username(&self) -> Result<String, TelegramError> {
+        if let Some(username) = self.bot_username.get() {
+            return Ok(username.clone());
+        }
+        let username = self.get_me().await?.username.unwrap_or_default();
+        Ok(self.bot_username.get_or_init(|| username).clone())
+    }
+
+    pub async fn is_chat_admin(&self, chat_id: i64, user_id: i64) -> Result<bool, TelegramError> {
         let payload = serde_json::json!({
             "chat_id": chat_id,
             "user_id": user_id,
@@ -351,3 +966,57 @@ impl Telegram {
         Ok(chat_member.status == "administrator" || chat_member.status == "creator")
     }
 }
+
+pub fn allowed_updates_from_env() -> Vec<String> {
+    match std::env::var("ALLOWED_UPDATES") {
+        Ok(value) => value
+            .split(',')
+            .map(|kind| kind.trim().to_string())
+            .filter(|kind| !kind.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_ALLOWED_UPDATES
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_api::{settle, ClockHold, MockBotApi};
+    use tokio::time::advance;
+
+    #[test]
+    fn test_chat_permissions_from_names() {
+        let permissions = ChatPermissions::from_names(["messages", "photos", "polls"]).unwrap();
+        assert!(permissions.can_send_messages && permissions.can_send_photos);
+        assert!(!permissions.can_pin_messages && !permissions.can_change_info);
+        assert_eq!(
+            permissions.granted_names(),
+            vec!["messages", "photos", "polls"]
+        );
+        assert_eq!(ChatPermissions::from_names(["admin"]), Err("admin"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limited_call_waits_before_retrying() {
+        let _clock = ClockHold::new();
+        let api = MockBotApi::start().await;
+        let telegram_client = api.telegram();
+        api.rate_limit_next("banChatMember", 5);
+
+        let ban = tokio::spawn(async move { telegram_client.ban_chat_member(-100123, 42).await });
+        api.wait_for_call("banChatMember").await;
+        settle().await;
+
+        // Not a message, so it is the global slot that has to wait
+        advance(Duration::from_secs(4)).await;
+        settle().await;
+        assert_eq!(api.calls("banChatMember").len(), 1);
+
+        advance(Duration::from_secs(1)).await;
+        api.wait_for_calls("banChatMember", 2).await;
+        assert!(ban.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,164 @@
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use std::env;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::dispatcher::{AppState, Dispatcher};
+use crate::telegram::{allowed_updates_from_env, Update};
+
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+// --- Webhook mode: Telegram pushes updates to an embedded HTTP server ---
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,          // Public base URL, as reachable by Telegram
+    pub listen: String,       // Local address the HTTP server binds to
+    pub path: String,         // Secret path the updates are posted to
+    pub secret_token: String, // Expected X-Telegram-Bot-Api-Secret-Token value
+    pub allowed_updates: Vec<String>,
+}
+
+impl WebhookConfig {
+    // Webhook mode is enabled by setting WEBHOOK_URL; otherwise the bot keeps polling
+    pub fn from_env() -> Option<Self> {
+        let url = env::var("WEBHOOK_URL").ok()?;
+        let listen = env::var("WEBHOOK_LISTEN").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
+        let path = env::var("WEBHOOK_PATH")
+            .unwrap_or_else(|_| format!("/webhook/{}", Uuid::new_v4().simple()));
+        let path = if path.starts_with('/') {
+            path
+        } else {
+            format!("/{}", path)
+        };
+        let secret_token =
+            env::var("WEBHOOK_SECRET").unwrap_or_else(|_| Uuid::new_v4().simple().to_string());
+        Some(WebhookConfig {
+            url: url.trim_end_matches('/').to_string(),
+            listen,
+            path,
+            secret_token,
+            allowed_updates: allowed_updates_from_env(),
+        })
+    }
+
+    pub fn webhook_url(&self) -> String {
+        format!("{}{}", self.url, self.path)
+    }
+}
+
+#[derive(Clone)]
+struct WebhookState {
+    secret_token: String,
+    dispatcher: Arc<Dispatcher>,
+}
+
+pub async fn run_webhook(
+    config: WebhookConfig,
+    app: AppState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if !config.url.starts_with("https://") && !app.telegram_client.is_local_server() {
+        warn!(
+            "api.telegram.org only delivers webhooks over HTTPS, but WEBHOOK_URL is {}",
+            config.url
+        );
+    }
+
+    let listener = tokio::net::TcpListener::bind(&config.listen).await?;
+    info!("🌐 Webhook server listening on {}", config.listen);
+
+    app.telegram_client
+        .set_webhook(
+            &config.webhook_url(),
+            &config.secret_token,
+            &config.allowed_updates,
+        )
+        .await?;
+    info!("🔗 Webhook registered with Telegram");
+
+    let state = WebhookState {
+        secret_token: config.secret_token.clone(),
+        dispatcher: Dispatcher::from_env(app.clone()),
+    };
+    let router = Router::new()
+        .route(&config.path, post(receive_update))
+        .with_state(state);
+
+    let result = axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown_signal())
+        .await;
+
+    info!("🛑 Shutting down, removing webhook");
+    if let Err(e) = app.telegram_client.delete_webhook().await {
+        error!("Failed to delete webhook: {}", e);
+    }
+    result.map_err(Into::into)
+}
+
+async fn receive_update(
+    State(state): State<WebhookState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    // Compared in constant time, so response timing says nothing about the secret
+    let token_matches = headers
+        .get(SECRET_TOKEN_HEADER)
+        .is_some_and(|value| bool::from(value.as_bytes().ct_eq(state.secret_token.as_bytes())));
+    if !token_matches {
+        warn!("Rejected webhook request with a missing or invalid secret token");
+        return StatusCode::UNAUTHORIZED;
+    }
+
+    match serde_json::from_slice::<Update>(&body) {
+        Ok(update) => {
+            debug!("Webhook update received: {}", update.update_id);
+            state.dispatcher.dispatch(update);
+        }
+        Err(e) => {
+            // Answer 200 anyway: Telegram would otherwise redeliver it forever
+            warn!(
+                "Failed to parse webhook update: {}. Body: {}",
+                e,
+                String::from_utf8_lossy(&body)
+            );
+        }
+    }
+    StatusCode::OK
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
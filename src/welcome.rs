@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+
+use crate::i18n::{tr, Language};
+use crate::telegram::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+// --- Welcome shown to a user who passes the challenge ---
+//
+// The template is plain text: it is escaped before the placeholders are filled in,
+// so admins cannot break the HTML the message is sent as.
+
+// Image challenges carry the text in a caption, which Telegram caps at 1024 characters
+pub const MAX_TEMPLATE_CHARS: usize = 900;
+pub const MAX_BUTTONS: usize = 6;
+const MAX_BUTTON_TEXT_CHARS: usize = 64;
+pub const PLACEHOLDERS: &[&str] = &["name", "mention", "chat", "rules_link"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WelcomeButton {
+    pub text: String,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WelcomeConfig {
+    pub template: Option<String>, // The catalog's "passed" message when unset
+    pub buttons: Vec<WelcomeButton>,
+    pub rules_link: Option<String>,
+    pub keep: bool, // Left in the chat instead of removed after the cleanup delay
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WelcomeError {
+    EmptyTemplate,
+    TemplateTooLong,
+    UnknownPlaceholder(String),
+    BadButtonText,
+    BadUrl,
+    TooManyButtons,
+}
+
+impl WelcomeError {
+    pub fn describe(&self, language: Language) -> String {
+        match self {
+            WelcomeError::EmptyTemplate => tr!(language, "commands.welcome_empty"),
+            WelcomeError::TemplateTooLong => {
+                tr!(
+                    language,
+                    "commands.welcome_too_long",
+                    max = MAX_TEMPLATE_CHARS
+                )
+            }
+            WelcomeError::UnknownPlaceholder(name) => tr!(
+                language,
+                "commands.welcome_unknown_placeholder",
+                name = escape_html(name),
+                placeholders = placeholder_list()
+            ),
+            WelcomeError::BadButtonText => tr!(
+                language,
+                "commands.welcome_bad_button_text",
+                max = MAX_BUTTON_TEXT_CHARS
+            ),
+            WelcomeError::BadUrl => tr!(language, "commands.welcome_bad_url"),
+            WelcomeError::TooManyButtons => {
+                tr!(
+                    language,
+                    "commands.welcome_too_many_buttons",
+                    max = MAX_BUTTONS
+                )
+            }
+        }
+    }
+}
+
+// "{name}, {mention}, ..." for the replies that explain the template
+pub fn placeholder_list() -> String {
+    PLACEHOLDERS
+        .iter()
+        .map(|name| format!("{{{}}}", name))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Replaces the known {placeholders} in one pass, so a value that happens to
+// contain one is left alone; anything else between braces stays as written
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after.find('}').and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &after[..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+pub fn validate_template(template: &str) -> Result<(), WelcomeError> {
+    if template.trim().is_empty() {
+        return Err(WelcomeError::EmptyTemplate);
+    }
+    if template.chars().count() > MAX_TEMPLATE_CHARS {
+        return Err(WelcomeError::TemplateTooLong);
+    }
+    // Only {words} count as placeholders, so braces can still be written
+    for candidate in template.split('{').skip(1) {
+        let Some((name, _)) = candidate.split_once('}') else {
+            continue;
+        };
+        let is_word =
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if is_word && !PLACEHOLDERS.contains(&name) {
+            return Err(WelcomeError::UnknownPlaceholder(name.to_string()));
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_url(url: &str) -> Result<(), WelcomeError> {
+    let has_scheme = ["https://", "http://", "tg://"]
+        .iter()
+        .any(|scheme| url.len() > scheme.len() && url.starts_with(scheme));
+    if has_scheme && !url.contains(char::is_whitespace) {
+        Ok(())
+    } else {
+        Err(WelcomeError::BadUrl)
+    }
+}
+
+pub fn new_button(
+    text: &str,
+    url: &str,
+    existing: &[WelcomeButton],
+) -> Result<WelcomeButton, WelcomeError> {
+    let text = text.trim();
+    if text.is_empty() || text.chars().count() > MAX_BUTTON_TEXT_CHARS {
+        return Err(WelcomeError::BadButtonText);
+    }
+    validate_url(url)?;
+    if existing.len() >= MAX_BUTTONS {
+        return Err(WelcomeError::TooManyButtons);
+    }
+    Ok(WelcomeButton {
+        text: text.to_string(),
+        url: url.to_string(),
+    })
+}
+
+impl WelcomeConfig {
+    pub fn render(
+        &self,
+        language: Language,
+        user_id: i64,
+        first_name: &str,
+        chat_title: Option<&str>,
+    ) -> String {
+        let name = escape_html(first_name);
+        let Some(template) = &self.template else {
+            return tr!(language, "result.passed", name = name);
+        };
+        let mention = format!("<a href=\"tg://user?id={}\">{}</a>", user_id, name);
+        let chat = chat_title
+            .map(escape_html)
+            .unwrap_or_else(|| tr!(language, "challenge.default_chat_title"));
+        let rules_link = self
+            .rules_link
+            .as_deref()
+            .map(escape_html)
+            .unwrap_or_default();
+        fill(
+            &escape_html(template),
+            &[
+                ("name", &name),
+                ("mention", &mention),
+                ("chat", &chat),
+                ("rules_link", &rules_link),
+            ],
+        )
+    }
+
+    // One button per row
+    pub fn keyboard(&self) -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup {
+            inline_keyboard: self
+                .buttons
+                .iter()
+                .map(|button| {
+                    vec![InlineKeyboardButton {
+                        text: button.text.clone(),
+                        url: Some(button.url.clone()),
+                        callback_data: None,
+                    }]
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_template_placeholders_are_filled_and_escaped() {
+        let welcome = WelcomeConfig {
+            template: Some("Hola {mention}, lee {rules_link} & disfruta de {chat} {json}".into()),
+            rules_link: Some("https://example.org/rules".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            welcome.render(Language::Es, 42, "<Ana {chat}>", Some("Rust")),
+            "Hola <a href=\"tg://user?id=42\">&lt;Ana {chat}&gt;</a>, lee https://example.org/rules &amp; disfruta de Rust {json}"
+        );
+        assert_eq!(
+            WelcomeConfig::default().render(Language::En, 42, "Ana", None),
+            "<b>Ana</b> has passed the verification. Welcome!"
+        );
+    }
+
+    #[test]
+    fn test_invalid_templates_and_buttons_are_rejected() {
+        assert_eq!(validate_template("  "), Err(WelcomeError::EmptyTemplate));
+        assert_eq!(
+            validate_template("Hola {nombre}"),
+            Err(WelcomeError::UnknownPlaceholder("nombre".into()))
+        );
+        assert!(validate_template("Hola {name} :-{ }").is_ok());
+        assert_eq!(
+            validate_template(&"x".repeat(MAX_TEMPLATE_CHARS + 1)),
+            Err(WelcomeError::TemplateTooLong)
+        );
+
+        assert_eq!(
+            new_button("Reglas", "example.org", &[]),
+            Err(WelcomeError::BadUrl)
+        );
+        assert_eq!(
+            new_button(" ", "https://example.org", &[]),
+            Err(WelcomeError::BadButtonText)
+        );
+        let button = new_button("Reglas", "https://example.org", &[]).unwrap();
+        let full = vec![button; MAX_BUTTONS];
+        assert_eq!(
+            new_button("Web", "https://example.org", &full),
+            Err(WelcomeError::TooManyButtons)
+        );
+    }
+}